@@ -0,0 +1,12 @@
+-- Time series of every value change a feature has had
+
+CREATE TABLE "history" (
+	"device"	TEXT NOT NULL,
+	"feature"	TEXT NOT NULL,
+	"time"	INTEGER NOT NULL,
+	"value"	TEXT,
+	"error"	TEXT,
+	FOREIGN KEY("device", "feature") REFERENCES "feature"("device", "id") ON DELETE CASCADE
+);
+
+CREATE INDEX "history_feature_time" ON "history" ("device", "feature", "time");
@@ -0,0 +1,3 @@
+-- Last known value of a feature so it can be restored on startup
+
+ALTER TABLE "feature" ADD COLUMN "last_value" TEXT;
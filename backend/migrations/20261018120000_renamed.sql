@@ -0,0 +1,4 @@
+-- Names set by the user, integrations saving the device or feature again keep them
+
+ALTER TABLE "device" ADD COLUMN "renamed" BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE "feature" ADD COLUMN "renamed" BOOLEAN NOT NULL DEFAULT 0;
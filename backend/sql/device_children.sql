@@ -0,0 +1,2 @@
+SELECT id, name, type, parent, task_spec FROM device
+WHERE parent = ?;
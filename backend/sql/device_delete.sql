@@ -0,0 +1 @@
+DELETE FROM device WHERE id = ?1;
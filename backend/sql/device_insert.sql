@@ -1,6 +1,6 @@
 INSERT INTO device (id, name, type, parent,task_spec) VALUES (?,?,?,?,?) 
 ON CONFLICT (id) DO UPDATE 
-    SET name=excluded.name, 
+    SET name=CASE WHEN renamed THEN name ELSE excluded.name END, 
         parent=excluded.parent,
         type=excluded.type,
         task_spec=excluded.task_spec
\ No newline at end of file
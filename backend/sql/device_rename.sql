@@ -0,0 +1 @@
+UPDATE device SET name = ?2, renamed = 1 WHERE id = ?1;
@@ -0,0 +1 @@
+UPDATE device SET parent = ?2 WHERE id = ?1;
@@ -0,0 +1 @@
+DELETE FROM feature WHERE device = ?1 AND id = ?2;
@@ -0,0 +1,3 @@
+DELETE FROM feature
+WHERE device = ?1 AND virtual = false AND id NOT IN (SELECT value FROM json_each(?2))
+RETURNING id;
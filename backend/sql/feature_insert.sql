@@ -1,7 +1,7 @@
 INSERT INTO feature (device, id, name, virtual, direction, kind, meta, automate) VALUES (?, ?, ?, ?, ?, ?, ?, ?) 
 ON CONFLICT (device, id) DO 
     UPDATE SET direction=excluded.direction, 
-               name=excluded.name,
+               name=CASE WHEN renamed THEN name ELSE excluded.name END,
                kind=excluded.kind, 
                meta=excluded.meta,
                virtual=excluded.virtual,
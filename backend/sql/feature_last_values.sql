@@ -0,0 +1 @@
+SELECT device, id, last_value FROM feature WHERE last_value IS NOT NULL
\ No newline at end of file
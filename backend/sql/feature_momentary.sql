@@ -0,0 +1,2 @@
+SELECT device, id FROM feature
+WHERE json_extract(meta, '$.momentary') = 1
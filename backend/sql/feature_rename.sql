@@ -0,0 +1 @@
+UPDATE feature SET name = ?3, renamed = 1 WHERE device = ?1 AND id = ?2;
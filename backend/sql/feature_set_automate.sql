@@ -0,0 +1,2 @@
+UPDATE feature SET automate = ?3
+WHERE device = ?1 AND id = ?2
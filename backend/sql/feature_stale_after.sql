@@ -0,0 +1,2 @@
+SELECT device, id, CAST(json_extract(meta, '$.stale_after') AS REAL) AS stale_after FROM feature
+WHERE json_extract(meta, '$.stale_after') > 0
\ No newline at end of file
@@ -0,0 +1,2 @@
+UPDATE feature SET last_value = ?3
+WHERE device = ?1 AND id = ?2 AND (virtual = true OR direction IN (2, 3))
\ No newline at end of file
@@ -0,0 +1,10 @@
+SELECT (time / ?5) * ?5 AS bucket,
+       CAST(MIN(json_extract(value, '$')) AS REAL) AS min,
+       CAST(MAX(json_extract(value, '$')) AS REAL) AS max,
+       AVG(json_extract(value, '$')) AS avg,
+       COUNT(*) AS count
+FROM history
+WHERE device = ?1 AND feature = ?2 AND time >= ?3 AND time < ?4
+    AND json_type(value) IN ('integer', 'real', 'true', 'false')
+GROUP BY bucket
+ORDER BY bucket
\ No newline at end of file
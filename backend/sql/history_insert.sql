@@ -0,0 +1,3 @@
+INSERT INTO history (device, feature, time, value, error)
+SELECT ?1, ?2, ?3, ?4, ?5 FROM feature
+WHERE device = ?1 AND id = ?2 AND COALESCE(json_extract(meta, '$.history_retention'), 1) > 0
\ No newline at end of file
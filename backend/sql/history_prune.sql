@@ -0,0 +1,5 @@
+DELETE FROM history WHERE rowid IN (
+    SELECT history.rowid FROM history
+    JOIN feature ON feature.device = history.device AND feature.id = history.feature
+    WHERE history.time < ?1 - COALESCE(json_extract(feature.meta, '$.history_retention'), ?2) * 86400000
+)
\ No newline at end of file
@@ -0,0 +1,3 @@
+SELECT time, value, error FROM history
+WHERE device = ?1 AND feature = ?2 AND time >= ?3 AND time < ?4
+ORDER BY time
\ No newline at end of file
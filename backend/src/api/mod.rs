@@ -3,15 +3,20 @@ use std::sync::Arc;
 use futures::TryStreamExt;
 
 use anyhow::Result;
-use async_graphql::{Context, Object, Schema, SimpleObject, Subscription};
+use async_graphql::{Context, InputObject, Object, Schema, SimpleObject, Subscription};
 use futures::{Stream, StreamExt};
 use serde_json::{json, Value as Json};
+use time::OffsetDateTime;
 
 use crate::{
     db,
     device::{spawn_automation_task, Automation},
-    integration::zigbee2mqtt,
-    io::mqtt::MqttServerInfo,
+    integration::{
+        homeassistant,
+        mqtt::{self, MqttDevice},
+        zigbee2mqtt,
+    },
+    io::mqtt::{MqttServerInfo, MqttTls},
     task::Task,
     value::ValueId,
 };
@@ -21,7 +26,7 @@ pub struct Query;
 #[Object]
 impl Query {
     /// Get all or a specific device
-    async fn device<'c>(&self, id: Option<String>) -> Result<Vec<Device>> {
+    async fn device(&self, id: Option<String>) -> Result<Vec<Device>> {
         let mut conn = db::connection().await?;
 
         if let Some(id) = id {
@@ -37,15 +42,45 @@ impl Query {
             Ok(vec)
         }
     }
+    /// Status of all background tasks, like device tasks and automations
+    async fn tasks<'c>(&self, ctx: &Context<'c>) -> Vec<crate::task::TaskStatus> {
+        ctx.data_unchecked::<Task>().status()
+    }
+    /// Recorded values of a feature, defaults to the last 24 hours
+    /// If bucket is set in seconds, numeric values are summarised with min, max and avg per bucket
+    async fn history(
+        &self,
+        device_id: String,
+        feature_id: String,
+        from: Option<OffsetDateTime>,
+        to: Option<OffsetDateTime>,
+        bucket: Option<u32>,
+    ) -> Result<Vec<crate::history::Point>> {
+        let mut conn = db::connection().await?;
+
+        let to = to.unwrap_or_else(OffsetDateTime::now_utc);
+        let from = from.unwrap_or(to - time::Duration::DAY);
+        let bucket = bucket.map(|s| std::time::Duration::from_secs(s.into()));
+
+        let points =
+            crate::history::load(&device_id, &feature_id, from, to, bucket, &mut conn).await?;
+
+        Ok(points)
+    }
 }
 
 // This is just not to poulte this namespace with a bunch of short super generic symbols
 mod inner_value {
-    use async_graphql::{Object, Union};
+    use async_graphql::{Object, SimpleObject, Union};
     use serde_json::Value as Json;
+    use time::OffsetDateTime;
+
+    use crate::value::Current;
 
     pub struct Ok {
         value: Json,
+        last_updated: Option<OffsetDateTime>,
+        last_changed: Option<OffsetDateTime>,
     }
 
     #[Object]
@@ -53,10 +88,42 @@ mod inner_value {
         async fn value(&self) -> &'_ Json {
             &self.value
         }
+        /// The fields of an object value, null for other values
+        async fn fields(&self) -> Option<Vec<Field<'_>>> {
+            let object = self.value.as_object()?;
+
+            Some(
+                object
+                    .iter()
+                    .map(|(name, value)| Field { name, value })
+                    .collect(),
+            )
+        }
+        /// The items of an array value, null for other values
+        async fn items(&self) -> Option<&Vec<Json>> {
+            self.value.as_array()
+        }
+        /// Last time the value was reported, null if not reported since startup
+        async fn last_updated(&self) -> Option<OffsetDateTime> {
+            self.last_updated
+        }
+        /// Last time the value changed, null if not changed since startup
+        async fn last_changed(&self) -> Option<OffsetDateTime> {
+            self.last_changed
+        }
+    }
+
+    #[derive(SimpleObject)]
+    /// A field of an object value
+    pub struct Field<'a> {
+        name: &'a str,
+        value: &'a Json,
     }
 
     pub struct Err {
         value: String,
+        last_updated: Option<OffsetDateTime>,
+        last_changed: Option<OffsetDateTime>,
     }
 
     #[Object]
@@ -64,6 +131,14 @@ mod inner_value {
         async fn message(&self) -> &'_ str {
             &self.value
         }
+        /// Last time the value was reported, null if not reported since startup
+        async fn last_updated(&self) -> Option<OffsetDateTime> {
+            self.last_updated
+        }
+        /// Last time the value changed, null if not changed since startup
+        async fn last_changed(&self) -> Option<OffsetDateTime> {
+            self.last_changed
+        }
     }
 
     #[derive(Union)]
@@ -72,11 +147,25 @@ mod inner_value {
         Err(Err),
     }
 
-    impl From<Result<Json, String>> for Value {
-        fn from(fv: Result<Json, String>) -> Self {
-            match fv {
-                Ok(value) => Value::Ok(Ok { value }),
-                Err(value) => Value::Err(Err { value }),
+    impl From<Current> for Value {
+        fn from(current: Current) -> Self {
+            let Current {
+                value,
+                last_updated,
+                last_changed,
+            } = current;
+
+            match value {
+                Ok(value) => Value::Ok(Ok {
+                    value,
+                    last_updated,
+                    last_changed,
+                }),
+                Err(value) => Value::Err(Err {
+                    value,
+                    last_updated,
+                    last_changed,
+                }),
             }
         }
     }
@@ -100,21 +189,15 @@ struct Device {
     inner: DeviceInner,
 }
 
-#[derive(SimpleObject)]
-struct DeviceType {
-    kind: String,
-    name: Option<String>,
-}
-
 enum DeviceInner {
     Arc(Arc<crate::device::Device>),
-    Owned(crate::device::Device),
+    Owned(Box<crate::device::Device>),
 }
 
 impl From<crate::device::Device> for Device {
     fn from(d: crate::device::Device) -> Self {
         Device {
-            inner: DeviceInner::Owned(d),
+            inner: DeviceInner::Owned(Box::new(d)),
         }
     }
 }
@@ -158,7 +241,7 @@ impl Device {
         Ok(json)
     }
     /// All the features a device exposes
-    async fn features<'c>(&self) -> Result<Vec<Feature>> {
+    async fn features(&self) -> Result<Vec<Feature<'_>>> {
         let mut conn = db::connection().await?;
         let device_id = &self.borrow().id;
 
@@ -196,6 +279,7 @@ impl<'a> Feature<'a> {
     }
     /// Json metadata about the feature
     /// Common meta data is Number unit a list of possible States for state
+    /// and stale_after in seconds for features that should report regularly
     async fn meta(&self) -> &Json {
         &self.inner.meta
     }
@@ -214,6 +298,139 @@ impl<'a> Feature<'a> {
     }
 }
 
+/// TLS settings for a MQTT server, all paths point to PEM files on the server
+#[derive(InputObject)]
+struct TlsInput {
+    /// CA to trust, the system roots are used if not set
+    ca_file: Option<String>,
+    /// Certificate to authenticate with, requires client_key_file
+    client_cert_file: Option<String>,
+    client_key_file: Option<String>,
+    /// Skip verifying the server certificate
+    #[graphql(default)]
+    insecure: bool,
+}
+
+impl From<TlsInput> for MqttTls {
+    fn from(t: TlsInput) -> Self {
+        MqttTls {
+            ca_file: t.ca_file,
+            client_cert_file: t.client_cert_file,
+            client_key_file: t.client_key_file,
+            insecure: t.insecure,
+        }
+    }
+}
+
+/// Where to find a MQTT server and how to log in
+#[derive(InputObject)]
+struct MqttServerInput {
+    host: String,
+    /// Defaults to 1883, or 8883 with TLS
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    tls: Option<TlsInput>,
+}
+
+impl From<MqttServerInput> for MqttServerInfo {
+    fn from(s: MqttServerInput) -> Self {
+        let default_port = if s.tls.is_some() { 8883 } else { 1883 };
+
+        MqttServerInfo::new(
+            s.host,
+            s.port.unwrap_or(default_port),
+            s.username,
+            s.password,
+            s.tls.map(MqttTls::from),
+        )
+    }
+}
+
+/// A device speaking plain MQTT
+#[derive(InputObject)]
+struct MqttDeviceInput {
+    name: String,
+    server: MqttServerInput,
+    /// Topic the device publishes its state to
+    state_topic: String,
+    /// Topic to publish writes to, the device is read only without it
+    command_topic: Option<String>,
+    /// Payload to publish, `{{value}}` and `{{feature}}` are replaced,
+    /// without it the value is published as is or nested at the feature pointer
+    payload_template: Option<String>,
+    features: Vec<MqttFeatureInput>,
+}
+
+impl MqttDeviceInput {
+    fn into_parts(self) -> (String, MqttDevice, Vec<crate::device::Feature>) {
+        let spec = MqttDevice {
+            server: self.server.into(),
+            state_topic: self.state_topic,
+            command_topic: self.command_topic,
+            payload_template: self.payload_template,
+        };
+
+        let features = self.features.into_iter().map(Into::into).collect();
+
+        (self.name, spec, features)
+    }
+}
+
+/// A feature of a MQTT device
+#[derive(InputObject)]
+struct MqttFeatureInput {
+    id: String,
+    name: String,
+    kind: crate::device::ValueKind,
+    /// Defaults to SOURCE_SINK
+    direction: Option<crate::device::ValueDirection>,
+    /// JSON pointer to the value in the payload, the whole payload is the value if not set
+    pointer: Option<String>,
+    /// Overrides the topic of the device
+    state_topic: Option<String>,
+    /// Overrides the topic of the device
+    command_topic: Option<String>,
+    /// Overrides the template of the device
+    payload_template: Option<String>,
+    /// Extra meta like unit, possible, value_on and value_off
+    meta: Option<Json>,
+}
+
+impl From<MqttFeatureInput> for crate::device::Feature {
+    fn from(f: MqttFeatureInput) -> Self {
+        let mut meta = match f.meta {
+            Some(Json::Object(meta)) => meta,
+            _ => Default::default(),
+        };
+
+        let mapping = [
+            ("pointer", f.pointer),
+            ("state_topic", f.state_topic),
+            ("command_topic", f.command_topic),
+            ("payload_template", f.payload_template),
+        ];
+
+        for (key, value) in mapping {
+            if let Some(value) = value {
+                meta.insert(key.into(), Json::String(value));
+            }
+        }
+
+        crate::device::Feature {
+            id: f.id,
+            name: f.name,
+            virt: false,
+            direction: f
+                .direction
+                .unwrap_or(crate::device::ValueDirection::SourceSink),
+            kind: f.kind,
+            meta: meta.into(),
+            automate: None,
+        }
+    }
+}
+
 pub struct Mutation;
 
 #[Object]
@@ -230,6 +447,45 @@ impl Mutation {
 
         Ok(id)
     }
+    /// Create a virtual button, push `true` to its feature to press it
+    async fn button_device(&self, name: String) -> Result<String> {
+        let mut conn = db::connection().await?;
+        let dev = crate::device::Device::create_button(name, &mut conn).await?;
+
+        let id = dev.id.clone();
+
+        crate::device::notify_changed(dev);
+
+        Ok(id)
+    }
+    /// Create a virtual slider with a number between min and max
+    async fn slider_device(
+        &self,
+        name: String,
+        min: f64,
+        max: f64,
+        step: Option<f64>,
+    ) -> Result<String> {
+        let mut conn = db::connection().await?;
+        let dev = crate::device::Device::create_slider(name, min, max, step, &mut conn).await?;
+
+        let id = dev.id.clone();
+
+        crate::device::notify_changed(dev);
+
+        Ok(id)
+    }
+    /// Create a virtual on/off toggle
+    async fn toggle_device(&self, name: String) -> Result<String> {
+        let mut conn = db::connection().await?;
+        let dev = crate::device::Device::create_toggle(name, &mut conn).await?;
+
+        let id = dev.id.clone();
+
+        crate::device::notify_changed(dev);
+
+        Ok(id)
+    }
     /// Create a value buffer on the target device
     /// this device must exist
     async fn value_buffer(
@@ -303,6 +559,68 @@ impl Mutation {
 
         Ok(true)
     }
+    /// Set the UTC offset in minutes of the clock device
+    /// If the clock device does not exist, create it
+    async fn clock<'c>(&self, ctx: &Context<'c>, utc_offset: Option<i32>) -> Result<bool> {
+        let task = ctx.data_unchecked::<Task>();
+
+        let utc_offset = utc_offset.unwrap_or(0);
+        anyhow::ensure!(
+            utc_offset.abs() < 24 * 60,
+            "UTC offset must be less than a day"
+        );
+
+        let dev = crate::device::Device {
+            id: "theclock".into(),
+            name: "The Clock".into(),
+            parent: None,
+            device_type: crate::device::DeviceType::Hardware,
+            task_spec: crate::device::TaskSpec::Clock { utc_offset },
+        };
+
+        let source = |id: &str, name: &str, kind, meta| crate::device::Feature {
+            id: id.into(),
+            name: name.into(),
+            virt: false,
+            direction: crate::device::ValueDirection::Source,
+            kind,
+            meta,
+            automate: None,
+        };
+
+        use crate::device::ValueKind;
+
+        let features = [
+            source("hour", "Hour", ValueKind::Number, json!({})),
+            source("minute", "Minute", ValueKind::Number, json!({})),
+            source(
+                "weekday",
+                "Weekday",
+                ValueKind::State,
+                json!({
+                    "possible": ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"],
+                }),
+            ),
+            source("is_weekend", "Is weekend", ValueKind::Bool, json!({})),
+            source("date", "Date", ValueKind::String, json!({})),
+        ];
+
+        let mut txn = db::begin().await?;
+
+        dev.save(&mut txn).await?;
+
+        for feature in &features {
+            feature.save(&dev.id, &mut txn).await?;
+        }
+
+        txn.commit().await?;
+
+        crate::device::spawn_device_tasks(task, &dev);
+
+        crate::device::notify_changed(dev);
+
+        Ok(true)
+    }
     /// Add Zigbee2Mqtt integration
     async fn zigbee_2_mqtt<'c>(
         &self,
@@ -311,16 +629,257 @@ impl Mutation {
         port: Option<u16>,
         username: Option<String>,
         password: Option<String>,
+        tls: Option<TlsInput>,
     ) -> Result<Device> {
         let task = ctx.data_unchecked::<Task>();
 
-        let server_info = MqttServerInfo::new(host, port.unwrap_or(1883), username, password);
+        let default_port = if tls.is_some() { 8883 } else { 1883 };
+        let tls = tls.map(MqttTls::from);
+        let server_info =
+            MqttServerInfo::new(host, port.unwrap_or(default_port), username, password, tls);
         let mut conn = db::connection().await?;
 
         let device = zigbee2mqtt::create_integration_device(server_info, task, &mut conn).await?;
 
         Ok(device.into())
     }
+    /// Add devices announced with Home Assistant MQTT discovery,
+    /// the prefix defaults to `homeassistant`
+    async fn home_assistant_discovery<'c>(
+        &self,
+        ctx: &Context<'c>,
+        server: MqttServerInput,
+        prefix: Option<String>,
+    ) -> Result<Device> {
+        let task = ctx.data_unchecked::<Task>();
+
+        let prefix = prefix.unwrap_or_else(|| homeassistant::DEFAULT_PREFIX.into());
+        let mut conn = db::connection().await?;
+
+        let device =
+            homeassistant::create_integration_device(server.into(), prefix, task, &mut conn)
+                .await?;
+
+        Ok(device.into())
+    }
+    /// Publish devices as Home Assistant MQTT discovery configs, the prefix defaults
+    /// to `homeassistant` and states and commands are under `bramble` unless topic is set
+    async fn home_assistant_export<'c>(
+        &self,
+        ctx: &Context<'c>,
+        server: MqttServerInput,
+        prefix: Option<String>,
+        topic: Option<String>,
+    ) -> Result<Device> {
+        let task = ctx.data_unchecked::<Task>();
+
+        let prefix = prefix.unwrap_or_else(|| homeassistant::DEFAULT_PREFIX.into());
+        let topic = topic.unwrap_or_else(|| homeassistant::DEFAULT_EXPORT_TOPIC.into());
+        let mut conn = db::connection().await?;
+
+        let device =
+            homeassistant::create_export_device(server.into(), prefix, topic, task, &mut conn)
+                .await?;
+
+        Ok(device.into())
+    }
+    /// Add a device speaking plain MQTT
+    async fn mqtt_device<'c>(&self, ctx: &Context<'c>, device: MqttDeviceInput) -> Result<Device> {
+        let task = ctx.data_unchecked::<Task>();
+
+        let (name, spec, features) = device.into_parts();
+        let device = mqtt::save_device(None, name, spec, features, task).await?;
+
+        Ok(device.into())
+    }
+    /// Replace the settings and features of a MQTT device
+    async fn edit_mqtt_device<'c>(
+        &self,
+        ctx: &Context<'c>,
+        device_id: String,
+        device: MqttDeviceInput,
+    ) -> Result<Device> {
+        let task = ctx.data_unchecked::<Task>();
+
+        let (name, spec, features) = device.into_parts();
+        let device = mqtt::save_device(Some(device_id), name, spec, features, task).await?;
+
+        Ok(device.into())
+    }
+    /// Delete a device with its features, devices it controls go with it
+    async fn delete_device<'c>(&self, ctx: &Context<'c>, device_id: String) -> Result<bool> {
+        let task = ctx.data_unchecked::<Task>();
+
+        crate::device::delete_device(task, &device_id).await?;
+
+        Ok(true)
+    }
+    /// Change the display name of a device, integrations keep it when they sync
+    async fn rename_device(&self, device_id: String, name: String) -> Result<Device> {
+        let mut conn = db::connection().await?;
+
+        crate::device::Device::rename(&device_id, &name, &mut conn).await?;
+
+        let device = Arc::new(crate::device::Device::load_by_id(&device_id, &mut conn).await?);
+        crate::device::notify_changed(device.clone());
+
+        Ok(device.into())
+    }
+    /// Move a device under another device, or to the top level without a parent
+    async fn set_device_parent(
+        &self,
+        device_id: String,
+        parent_id: Option<String>,
+    ) -> Result<Device> {
+        let mut conn = db::connection().await?;
+
+        // Walk up from the new parent to make sure we do not create a loop
+        let mut ancestor = parent_id.clone();
+
+        while let Some(id) = ancestor {
+            anyhow::ensure!(
+                id != device_id,
+                "Device {device_id} can not be its own ancestor"
+            );
+
+            ancestor = crate::device::Device::load_by_id(&id, &mut conn)
+                .await?
+                .parent;
+        }
+
+        crate::device::Device::set_parent(&device_id, parent_id.as_deref(), &mut conn).await?;
+
+        let device = Arc::new(crate::device::Device::load_by_id(&device_id, &mut conn).await?);
+        crate::device::notify_changed(device.clone());
+
+        Ok(device.into())
+    }
+    /// Delete a feature with its automation and value, the device task restarts without it.
+    /// Features an integration brings along can not be deleted, they would come back
+    async fn delete_feature<'c>(
+        &self,
+        ctx: &Context<'c>,
+        device_id: String,
+        feature_id: String,
+    ) -> Result<bool> {
+        let task = ctx.data_unchecked::<Task>();
+
+        {
+            let mut conn = db::connection().await?;
+            let feature = crate::device::Feature::load(&device_id, &feature_id, &mut conn).await?;
+
+            // The integration would bring it back the next time it syncs
+            anyhow::ensure!(
+                feature.virt || !managed_by_integration(&device_id, &mut conn).await?,
+                "Feature {feature_id} of {device_id} comes from an integration and can not be deleted"
+            );
+        }
+
+        crate::device::delete_feature(task, &device_id, &feature_id).await?;
+
+        Ok(true)
+    }
+    /// Change the display name of a feature, integrations keep it when they sync
+    async fn rename_feature(
+        &self,
+        device_id: String,
+        feature_id: String,
+        name: String,
+    ) -> Result<Device> {
+        let mut conn = db::connection().await?;
+
+        crate::device::Feature::rename(&device_id, &feature_id, &name, &mut conn).await?;
+
+        let device = Arc::new(crate::device::Device::load_by_id(&device_id, &mut conn).await?);
+        crate::device::notify_changed(device.clone());
+
+        Ok(device.into())
+    }
+    /// Allow new devices to join the zigbee network for a number of seconds, 0 closes it again
+    async fn zigbee_2_mqtt_permit_join(
+        &self,
+        integration_id: String,
+        seconds: u32,
+    ) -> Result<Json> {
+        let mut conn = db::connection().await?;
+        let server = zigbee2mqtt::server_of(&integration_id, &mut conn).await?;
+
+        let payload = json!({ "value": seconds > 0, "time": seconds });
+
+        zigbee2mqtt::bridge::request(
+            &server,
+            "permit_join",
+            payload,
+            zigbee2mqtt::bridge::REQUEST_TIMEOUT,
+        )
+        .await
+    }
+    /// Rename a device in zigbee2mqtt, this also changes its topic
+    async fn zigbee_2_mqtt_rename_device(&self, device_id: String, name: String) -> Result<Json> {
+        let mut conn = db::connection().await?;
+        let server = zigbee2mqtt::server_of(&device_id, &mut conn).await?;
+
+        let payload = json!({ "from": device_id, "to": name });
+
+        zigbee2mqtt::bridge::request(
+            &server,
+            "device/rename",
+            payload,
+            zigbee2mqtt::bridge::REQUEST_TIMEOUT,
+        )
+        .await
+    }
+    /// Remove a device from the zigbee network, force removes it even if it does not respond
+    async fn zigbee_2_mqtt_remove_device(
+        &self,
+        device_id: String,
+        #[graphql(default)] force: bool,
+    ) -> Result<Json> {
+        let mut conn = db::connection().await?;
+        let server = zigbee2mqtt::server_of(&device_id, &mut conn).await?;
+
+        let payload = json!({ "id": device_id, "force": force });
+
+        zigbee2mqtt::bridge::request(
+            &server,
+            "device/remove",
+            payload,
+            zigbee2mqtt::bridge::REQUEST_TIMEOUT,
+        )
+        .await
+    }
+    /// Ask a zigbee device to report the features that can be read back
+    async fn zigbee_2_mqtt_refresh(&self, device_id: String) -> Result<bool> {
+        zigbee2mqtt::refresh(&device_id).await?;
+
+        Ok(true)
+    }
+    /// Ask zigbee2mqtt if there is a firmware update for a device
+    async fn zigbee_2_mqtt_ota_check(&self, device_id: String) -> Result<Json> {
+        let mut conn = db::connection().await?;
+        let server = zigbee2mqtt::server_of(&device_id, &mut conn).await?;
+
+        zigbee2mqtt::bridge::request(
+            &server,
+            "device/ota_update/check",
+            json!({ "id": device_id }),
+            zigbee2mqtt::bridge::OTA_CHECK_TIMEOUT,
+        )
+        .await
+    }
+    /// Update the firmware of a device, this returns when the update is done
+    async fn zigbee_2_mqtt_ota_update(&self, device_id: String) -> Result<Json> {
+        let mut conn = db::connection().await?;
+        let server = zigbee2mqtt::server_of(&device_id, &mut conn).await?;
+
+        zigbee2mqtt::bridge::request(
+            &server,
+            "device/ota_update/update",
+            json!({ "id": device_id }),
+            zigbee2mqtt::bridge::OTA_TIMEOUT,
+        )
+        .await
+    }
     /// Add or change automation for a feature
     async fn automate<'c>(
         &self,
@@ -331,22 +890,61 @@ impl Mutation {
     ) -> Result<usize> {
         let task = ctx.data_unchecked::<Task>();
 
-        let program: Automation = serde_json::from_value(program)?;
+        let mut conn = db::connection().await?;
+        let feature = crate::device::Feature::load(&device_id, &feature_id, &mut conn).await?;
+
+        // Editing a paused automation keeps it paused unless told otherwise
+        let keep_enabled = program.get("enabled").is_none();
+        let mut program: Automation = serde_json::from_value(program)?;
+
+        if let (true, Some(old)) = (keep_enabled, &feature.automate) {
+            program.enabled = old.enabled;
+        }
 
         let target = ValueId::new(&device_id, &feature_id);
 
         spawn_automation_task(task, target, &program)?;
 
-        let mut conn = db::connection().await?;
-        let mut feature = crate::device::Feature::load(&device_id, &feature_id, &mut conn).await?;
-
-        feature.automate = Some(program);
-        feature.save(&device_id, &mut conn).await?;
+        crate::device::Feature::set_automate(&device_id, &feature_id, Some(&program), &mut conn)
+            .await?;
 
         notify_device_changed(&device_id).await?;
 
         Ok(0)
     }
+    /// Pause or resume the automation of a feature without losing it
+    async fn set_automation_enabled<'c>(
+        &self,
+        ctx: &Context<'c>,
+        device_id: String,
+        feature_id: String,
+        enabled: bool,
+    ) -> Result<bool> {
+        let task = ctx.data_unchecked::<Task>();
+        let target = ValueId::new(&device_id, &feature_id);
+
+        crate::device::set_automation_enabled(task, target, enabled).await?;
+
+        notify_device_changed(&device_id).await?;
+
+        Ok(enabled)
+    }
+    /// Remove the automation of a feature, returns false if it had none
+    async fn delete_automation<'c>(
+        &self,
+        ctx: &Context<'c>,
+        device_id: String,
+        feature_id: String,
+    ) -> Result<bool> {
+        let task = ctx.data_unchecked::<Task>();
+        let target = ValueId::new(&device_id, &feature_id);
+
+        let deleted = crate::device::delete_automation(task, target).await?;
+
+        notify_device_changed(&device_id).await?;
+
+        Ok(deleted)
+    }
 }
 
 // Helper fn to load a device and notify on the bus that it has changed
@@ -359,6 +957,22 @@ async fn notify_device_changed(id: &str) -> Result<()> {
     Ok(())
 }
 
+// Devices below an integration get their features from it
+async fn managed_by_integration(id: &str, conn: &mut sqlx::SqliteConnection) -> Result<bool> {
+    let device = crate::device::Device::load_by_id(id, conn).await?;
+
+    let Some(parent) = device.parent else {
+        return Ok(false);
+    };
+
+    let parent = crate::device::Device::load_by_id(&parent, conn).await?;
+
+    Ok(matches!(
+        parent.device_type,
+        crate::device::DeviceType::Integration { .. }
+    ))
+}
+
 pub struct Subscription;
 
 #[Subscription]
@@ -379,6 +993,19 @@ impl Subscription {
         tracing::debug!("GraphQL subscribe device updates");
         crate::device::changed().map(|d| d.into())
     }
+
+    /// Listen for tasks being spawned, restarted, failing or exiting
+    async fn tasks<'c>(
+        &self,
+        ctx: &Context<'c>,
+    ) -> impl Stream<Item = crate::task::TaskStatus> + '_ {
+        ctx.data_unchecked::<Task>().status_changes()
+    }
+
+    /// Listen for deleted devices, yields the id of the device
+    async fn device_removed(&self) -> impl Stream<Item = String> + '_ {
+        crate::device::removed().map(|d| d.id.clone())
+    }
 }
 
 pub type ApiSchema = Schema<Query, Mutation, Subscription>;
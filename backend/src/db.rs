@@ -45,3 +45,12 @@ pub async fn begin<'c>() -> Result<Transaction<'c, Sqlite>> {
     let connection = pool.begin().await?;
     Ok(connection)
 }
+
+/**
+ * Close the global db pool, waits for connections in use to be returned
+ */
+pub async fn close() {
+    if let Some(pool) = POOL.get() {
+        pool.close().await;
+    }
+}
@@ -1,4 +1,5 @@
 mod node;
+mod schedule;
 
 use std::collections::{BTreeSet, HashMap};
 
@@ -34,15 +35,49 @@ fn prop_to_node(target: ValueId, prop: &Properties) -> Box<dyn ProgramNode> {
         Xor => node0(node::xor),
         Latch => node1_mut(false, node::latch),
         MathCompare { operator } => node1(*operator, node::compare),
+        Add => node0(node::add),
+        Subtract => node0(node::subtract),
+        Multiply => node0(node::multiply),
+        Divide => node0(node::divide),
+        Modulo => node0(node::modulo),
+        Min => node0(node::min),
+        Max => node0(node::max),
+        Abs => node0(node::abs),
+        Round => node0(node::round),
+        Clamp => node0(node::clamp),
+        Scale => node0(node::scale),
+        Delay { seconds } => node1_mut(node::Delay::new(*seconds), node::delay),
+        Debounce { seconds } => node1_mut(node::Debounce::new(*seconds), node::debounce),
+        Throttle { seconds } => node1_mut(node::Throttle::new(*seconds), node::throttle),
+        Timeout { seconds } => node1_mut(node::Timeout::new(*seconds), node::timeout),
+        Schedule(schedule) => node1(schedule.clone(), node::schedule),
+        GetField { field } => node1(field.clone(), node::get_field),
+        SetField { field } => node1(field.clone(), node::set_field),
+        BuildObject { fields } => node1(fields.clone(), node::build_object),
+        Fresh {
+            device,
+            feature,
+            seconds,
+        } => node1(
+            node::Fresh::new(ValueId::new(device, feature), *seconds),
+            node::fresh,
+        ),
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Automation {
     counter: u32,
     nodes: Vec<Node>,
     connections: Vec<Connection>,
     defaults: Vec<(Slot, Json)>,
+    /// A disabled automation is kept but not run
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
 }
 
 impl Automation {
@@ -74,8 +109,13 @@ impl Automation {
 
         let dependencies = find_dependencies(&node, &connections);
 
+        // Schedules drive the program from the clock so they need no other data to react to
+        let timed = node
+            .iter()
+            .any(|n| matches!(n.properties, Properties::Schedule(_)));
+
         // The is a program but it does not react to any data, so again useless
-        if dependencies.is_empty() {
+        if dependencies.is_empty() && !timed {
             // There are no connections, program is useless but valid
             return Ok((Program::default(), vec![]));
         }
@@ -222,7 +262,7 @@ fn find_dependencies<'a>(nodes: &'a [&'a Node], connections: &'a [Connection]) -
         outgoing.entry(*f).or_default().insert(fs);
     }
 
-    nodes
+    let devices = nodes
         .iter()
         .filter_map(|n| match &n.properties {
             Properties::Device(d) => Some((n.id, d)),
@@ -232,8 +272,17 @@ fn find_dependencies<'a>(nodes: &'a [&'a Node], connections: &'a [Connection]) -
         .flat_map(|(set, dev)| {
             let dev: IString = dev.into();
             set.iter().map(move |&slot| ValueId::new(dev, slot))
-        })
-        .collect()
+        });
+
+    // Fresh nodes only look at when their value was reported, which is tracked for program inputs
+    let fresh = nodes.iter().filter_map(|n| match &n.properties {
+        Properties::Fresh {
+            device, feature, ..
+        } => Some(ValueId::new(device, feature)),
+        _ => None,
+    });
+
+    devices.chain(fresh).collect()
 }
 
 fn unique_connections(connections: &[Connection]) -> Vec<Connection> {
@@ -260,8 +309,13 @@ pub enum Properties {
 
     // Universal
     IsNull(String),
-    Equals { kind: String, meta: Option<Json> },
-    If { kind: String },
+    Equals {
+        kind: String,
+        meta: Option<Json>,
+    },
+    If {
+        kind: String,
+    },
 
     // Logic
     And,
@@ -272,10 +326,56 @@ pub enum Properties {
     Toggle,
 
     // Math
-    MathCompare { operator: CompareOp },
+    MathCompare {
+        operator: CompareOp,
+    },
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Min,
+    Max,
+    Abs,
+    Round,
+    Clamp,
+    Scale,
+
+    // Time
+    Delay {
+        seconds: f64,
+    },
+    Debounce {
+        seconds: f64,
+    },
+    Throttle {
+        seconds: f64,
+    },
+    Timeout {
+        seconds: f64,
+    },
+    Schedule(schedule::Schedule),
+
+    // Objects, a field starting with `/` is a JSON pointer into nested objects
+    GetField {
+        field: String,
+    },
+    SetField {
+        field: String,
+    },
+    BuildObject {
+        fields: Vec<String>,
+    },
+
+    // Availability
+    Fresh {
+        device: String,
+        feature: String,
+        seconds: f64,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Node {
     id: u32,
     position: (i64, i64),
@@ -284,10 +384,14 @@ pub struct Node {
 
 #[cfg(test)]
 mod test {
-    use std::collections::BTreeMap;
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, Instant},
+    };
 
     use super::*;
     use serde_json::json;
+    use time::macros::datetime;
 
     #[test]
     fn can_compile_automation() {
@@ -326,6 +430,7 @@ mod test {
             nodes,
             connections,
             defaults: vec![],
+            enabled: true,
         };
 
         let target = ValueId::new("", "state");
@@ -341,4 +446,377 @@ mod test {
 
         assert_eq!(out[&target], json!(true));
     }
+
+    #[test]
+    fn can_scale_brightness_to_percent() {
+        let nodes = vec![
+            Node {
+                id: 0,
+                position: (0, 0),
+                properties: Properties::Target,
+            },
+            Node {
+                id: 1,
+                position: (0, 0),
+                properties: Properties::Device("lamp".into()),
+            },
+            Node {
+                id: 2,
+                position: (0, 0),
+                properties: Properties::Scale,
+            },
+            Node {
+                id: 3,
+                position: (0, 0),
+                properties: Properties::Round,
+            },
+        ];
+
+        let connections = vec![
+            ((1, "brightness".into()), (2, "input".into())),
+            ((2, "result".into()), (3, "input".into())),
+            ((3, "result".into()), (0, "level".into())),
+        ];
+
+        let defaults = vec![
+            ((2, "in_min".into()), json!(0)),
+            ((2, "in_max".into()), json!(255)),
+            ((2, "out_min".into()), json!(0)),
+            ((2, "out_max".into()), json!(100)),
+        ];
+
+        let auto = Automation {
+            counter: 4,
+            nodes,
+            connections,
+            defaults,
+            enabled: true,
+        };
+
+        let target = ValueId::new("dimmer", "level");
+        let (mut program, deps) = auto.compile(target).unwrap();
+
+        assert_eq!(deps, vec![ValueId::new("lamp", "brightness")]);
+
+        let mut input = BTreeMap::new();
+
+        input.insert(ValueId::new("lamp", "brightness"), json!(255));
+        assert_eq!(program.execute(&input).unwrap()[&target], json!(100));
+
+        input.insert(ValueId::new("lamp", "brightness"), json!(128));
+        assert_eq!(program.execute(&input).unwrap()[&target], json!(50));
+    }
+
+    #[test]
+    fn divide_by_zero_is_reported() {
+        // The target gets either the result or the error slot of the divide node
+        let compile = |slot: &str| {
+            let nodes = vec![
+                Node {
+                    id: 0,
+                    position: (0, 0),
+                    properties: Properties::Target,
+                },
+                Node {
+                    id: 1,
+                    position: (0, 0),
+                    properties: Properties::Device("sensor".into()),
+                },
+                Node {
+                    id: 2,
+                    position: (0, 0),
+                    properties: Properties::Divide,
+                },
+            ];
+
+            let connections = vec![
+                ((1, "a".into()), (2, "input".into())),
+                ((1, "b".into()), (2, "other".into())),
+                ((2, slot.into()), (0, "value".into())),
+            ];
+
+            let auto = Automation {
+                counter: 3,
+                nodes,
+                connections,
+                defaults: vec![],
+                enabled: true,
+            };
+
+            auto.compile(ValueId::new("out", "value")).unwrap().0
+        };
+
+        let target = ValueId::new("out", "value");
+        let mut result = compile("result");
+        let mut error = compile("error");
+
+        let mut input = BTreeMap::new();
+
+        input.insert(ValueId::new("sensor", "a"), json!(10));
+        input.insert(ValueId::new("sensor", "b"), json!(4));
+        assert_eq!(result.execute(&input).unwrap()[&target], json!(2.5));
+        assert_eq!(error.execute(&input).unwrap()[&target], Json::Null);
+
+        input.insert(ValueId::new("sensor", "b"), json!(0));
+        assert_eq!(result.execute(&input).unwrap()[&target], Json::Null);
+        assert_eq!(
+            error.execute(&input).unwrap()[&target],
+            json!("division by zero")
+        );
+
+        input.insert(ValueId::new("sensor", "b"), json!("four"));
+        assert_eq!(result.execute(&input).unwrap()[&target], Json::Null);
+        assert!(error.execute(&input).unwrap()[&target].is_string());
+    }
+
+    #[test]
+    fn timeout_holds_after_motion() {
+        let nodes = vec![
+            Node {
+                id: 0,
+                position: (0, 0),
+                properties: Properties::Target,
+            },
+            Node {
+                id: 1,
+                position: (0, 0),
+                properties: Properties::Device("motion".into()),
+            },
+            Node {
+                id: 2,
+                position: (0, 0),
+                properties: Properties::Timeout { seconds: 300.0 },
+            },
+        ];
+
+        let connections = vec![
+            ((1, "occupancy".into()), (2, "input".into())),
+            ((2, "result".into()), (0, "state".into())),
+        ];
+
+        let auto = Automation {
+            counter: 3,
+            nodes,
+            connections,
+            defaults: vec![],
+            enabled: true,
+        };
+
+        let target = ValueId::new("light", "state");
+        let (mut program, _) = auto.compile(target).unwrap();
+
+        let start = Instant::now();
+        let mut input = BTreeMap::new();
+
+        input.insert(ValueId::new("motion", "occupancy"), json!(true));
+        let out = program.execute_at(&input, start).unwrap();
+        assert_eq!(out[&target], json!(true));
+        assert_eq!(program.wake(), None);
+
+        // Motion stops, the light should stay on and ask to be woken up
+        let stopped = start + Duration::from_secs(10);
+        input.insert(ValueId::new("motion", "occupancy"), json!(false));
+        let out = program.execute_at(&input, stopped).unwrap();
+        assert_eq!(out[&target], json!(true));
+        assert_eq!(program.wake(), Some(stopped + Duration::from_secs(300)));
+
+        // Nothing changed, nothing to push again
+        let out = program
+            .execute_changed_at(&input, stopped + Duration::from_secs(299))
+            .unwrap();
+        assert!(out.is_empty());
+
+        let out = program
+            .execute_changed_at(&input, stopped + Duration::from_secs(300))
+            .unwrap();
+        assert_eq!(out[&target], json!(false));
+        assert_eq!(program.wake(), None);
+    }
+
+    #[test]
+    fn can_build_color() {
+        let nodes = vec![
+            Node {
+                id: 0,
+                position: (0, 0),
+                properties: Properties::Target,
+            },
+            Node {
+                id: 1,
+                position: (0, 0),
+                properties: Properties::Device("remote".into()),
+            },
+            Node {
+                id: 2,
+                position: (0, 0),
+                properties: Properties::BuildObject {
+                    fields: vec!["x".into(), "y".into()],
+                },
+            },
+            Node {
+                id: 3,
+                position: (0, 0),
+                properties: Properties::SetField {
+                    field: "/extra/level".into(),
+                },
+            },
+            Node {
+                id: 4,
+                position: (0, 0),
+                properties: Properties::GetField { field: "x".into() },
+            },
+        ];
+
+        let connections = vec![
+            ((1, "x".into()), (2, "x".into())),
+            ((1, "y".into()), (2, "y".into())),
+            ((2, "result".into()), (3, "input".into())),
+            ((4, "result".into()), (3, "value".into())),
+            ((1, "color".into()), (4, "input".into())),
+            ((3, "result".into()), (0, "color".into())),
+        ];
+
+        let auto = Automation {
+            counter: 5,
+            nodes,
+            connections,
+            defaults: vec![],
+            enabled: true,
+        };
+
+        let target = ValueId::new("bulb", "color");
+        let (mut program, _) = auto.compile(target).unwrap();
+
+        let mut input = BTreeMap::new();
+
+        input.insert(ValueId::new("remote", "x"), json!(0.3));
+        input.insert(ValueId::new("remote", "y"), json!(0.4));
+        input.insert(ValueId::new("remote", "color"), json!({"x": 1}));
+        assert_eq!(
+            program.execute(&input).unwrap()[&target],
+            json!({"x": 0.3, "y": 0.4, "extra": {"level": 1}})
+        );
+
+        // Missing inputs are left out of the object
+        input.insert(ValueId::new("remote", "y"), Json::Null);
+        input.insert(ValueId::new("remote", "color"), json!("red"));
+        assert_eq!(
+            program.execute(&input).unwrap()[&target],
+            json!({"x": 0.3, "extra": {"level": null}})
+        );
+    }
+
+    #[test]
+    fn schedule_follows_the_program_clock() {
+        let schedule = serde_json::from_value(json!({
+            "rules": [{ "type": "weekly", "days": ["mon"], "from": "06:30", "to": "08:00" }],
+        }))
+        .unwrap();
+
+        let nodes = vec![
+            Node {
+                id: 0,
+                position: (0, 0),
+                properties: Properties::Target,
+            },
+            Node {
+                id: 1,
+                position: (0, 0),
+                properties: Properties::Schedule(schedule),
+            },
+        ];
+
+        let auto = Automation {
+            counter: 2,
+            nodes,
+            connections: vec![((1, "result".into()), (0, "state".into()))],
+            defaults: vec![],
+            enabled: true,
+        };
+
+        let target = ValueId::new("heater", "state");
+        let (mut program, _) = auto.compile(target).unwrap();
+
+        let start = Instant::now();
+        let input = BTreeMap::new();
+
+        // 2023-03-06 is a monday
+        let out = program
+            .execute_at_utc(&input, start, datetime!(2023-03-06 06:29:30 UTC))
+            .unwrap();
+        assert_eq!(out[&target], json!(false));
+        assert_eq!(program.wake(), Some(start + Duration::from_secs(30)));
+
+        let out = program
+            .execute_at_utc(
+                &input,
+                start + Duration::from_secs(30),
+                datetime!(2023-03-06 06:30 UTC),
+            )
+            .unwrap();
+        assert_eq!(out[&target], json!(true));
+    }
+
+    #[test]
+    fn fresh_follows_reports_and_the_program_clock() {
+        let nodes = vec![
+            Node {
+                id: 0,
+                position: (0, 0),
+                properties: Properties::Target,
+            },
+            Node {
+                id: 1,
+                position: (0, 0),
+                properties: Properties::Fresh {
+                    device: "sensor".into(),
+                    feature: "temperature".into(),
+                    seconds: 60.0,
+                },
+            },
+        ];
+
+        let auto = Automation {
+            counter: 2,
+            nodes,
+            connections: vec![((1, "result".into()), (0, "state".into()))],
+            defaults: vec![],
+            enabled: true,
+        };
+
+        let target = ValueId::new("alarm", "state");
+        let temperature = ValueId::new("sensor", "temperature");
+        let (mut program, deps) = auto.compile(target).unwrap();
+
+        assert_eq!(deps, vec![temperature]);
+
+        let start = Instant::now();
+        let utc = datetime!(2023-03-06 12:00 UTC);
+        let input = BTreeMap::from([(temperature, json!(21))]);
+
+        // Never reported
+        let out = program.execute_at_utc(&input, start, utc).unwrap();
+        assert_eq!(out[&target], json!(false));
+
+        program.set_updated(temperature, Some(utc - time::Duration::seconds(20)));
+
+        let out = program.execute_at_utc(&input, start, utc).unwrap();
+        assert_eq!(out[&target], json!(true));
+        assert_eq!(program.wake(), Some(start + Duration::from_secs(40)));
+
+        let out = program
+            .execute_at_utc(
+                &input,
+                start + Duration::from_secs(40),
+                utc + time::Duration::seconds(40),
+            )
+            .unwrap();
+        assert_eq!(out[&target], json!(false));
+
+        // Errors are never fresh
+        program.set_updated(temperature, None);
+
+        let out = program.execute_at_utc(&input, start, utc).unwrap();
+        assert_eq!(out[&target], json!(false));
+    }
 }
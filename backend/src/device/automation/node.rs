@@ -1,3 +1,8 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
 use crate::{
     program::{Inputs, Outputs, ProgramNode},
     strings::IString,
@@ -8,7 +13,7 @@ use anyhow::Result;
 use serde_json::{json, Value as Json};
 use tracing::debug;
 
-use super::CompareOp;
+use super::{schedule::Schedule, CompareOp};
 
 type NodeFn0 = fn(&Inputs, &mut Outputs) -> Result<()>;
 type NodeFn1Mut<T> = fn(&mut T, &Inputs, &mut Outputs) -> Result<()>;
@@ -76,7 +81,7 @@ pub fn device(id: &IString, input: &Inputs, output: &mut Outputs) -> Result<()>
 pub fn target(id: &ValueId, input: &Inputs, output: &mut Outputs) -> Result<()> {
     let v = input.slot_one(id.feature)?.unwrap_or(&Json::Null);
 
-    output.program(id.clone(), v.clone());
+    output.program(*id, v.clone());
 
     debug!("{:?} target with {:?}", id, v);
 
@@ -92,10 +97,7 @@ pub fn is_null(input: &Inputs, output: &mut Outputs) -> Result<()> {
 }
 
 pub fn or(input: &Inputs, output: &mut Outputs) -> Result<()> {
-    let out = input
-        .slot("input")?
-        .into_iter()
-        .any(|v| matches!(v, Json::Bool(true)));
+    let out = input.slot("input")?.any(|v| matches!(v, Json::Bool(true)));
 
     output.slot("result", json!(out));
 
@@ -103,10 +105,7 @@ pub fn or(input: &Inputs, output: &mut Outputs) -> Result<()> {
 }
 
 pub fn and(input: &Inputs, output: &mut Outputs) -> Result<()> {
-    let out = input
-        .slot("input")?
-        .into_iter()
-        .all(|v| matches!(v, Json::Bool(true)));
+    let out = input.slot("input")?.all(|v| matches!(v, Json::Bool(true)));
 
     output.slot("result", json!(out));
 
@@ -116,7 +115,6 @@ pub fn and(input: &Inputs, output: &mut Outputs) -> Result<()> {
 pub fn xor(input: &Inputs, output: &mut Outputs) -> Result<()> {
     let ones = input
         .slot("input")?
-        .into_iter()
         .filter(|v| matches!(v, Json::Bool(true)))
         .count();
 
@@ -231,3 +229,511 @@ pub fn alt(input: &Inputs, output: &mut Outputs) -> Result<()> {
 
     Ok(())
 }
+
+/// Read a numeric value from a slot, missing and non numeric values become an error message
+fn number(name: &str, value: &Json) -> Result<f64, String> {
+    match value {
+        Json::Null => Err(format!("{name} has no value")),
+        v => v
+            .as_f64()
+            .ok_or_else(|| format!("{name} is not a number, got {v}")),
+    }
+}
+
+/// Read all the numeric values connected to a slot
+fn numbers(name: &str, input: &Inputs) -> Result<Vec<f64>, String> {
+    let Ok(values) = input.slot(name) else {
+        return Err(format!("{name} is not connected"));
+    };
+
+    let numbers = values
+        .map(|v| number(name, v))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if numbers.is_empty() {
+        return Err(format!("{name} has no value"));
+    }
+
+    Ok(numbers)
+}
+
+/// Write the outcome of a math node, errors leave the result as null
+/// and put the reason on the error slot
+fn math_result(output: &mut Outputs, result: Result<f64, String>) {
+    let result = result.and_then(|v| {
+        if !v.is_finite() {
+            Err(format!("result {v} is not a finite number"))
+        } else if v.fract() == 0.0 && v.abs() < i64::MAX as f64 {
+            // Keep whole numbers as integers, devices are picky about 100.0 vs 100
+            Ok(json!(v as i64))
+        } else {
+            Ok(json!(v))
+        }
+    });
+
+    match result {
+        Ok(v) => {
+            output.slot("result", v);
+            output.slot("error", Json::Null);
+        }
+        Err(e) => {
+            output.slot("result", Json::Null);
+            output.slot("error", json!(e));
+        }
+    }
+}
+
+/// Read the two operands `input` and `other` for binary math operations
+fn operands(input: &Inputs) -> Result<(f64, f64), String> {
+    let a = number("input", input.slot_or("input", &Json::Null))?;
+    let b = number("other", input.slot_or("other", &Json::Null))?;
+
+    Ok((a, b))
+}
+
+pub fn add(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = numbers("input", input).map(|n| n.into_iter().sum());
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+pub fn multiply(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = numbers("input", input).map(|n| n.into_iter().product());
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+pub fn min(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = numbers("input", input).map(|n| n.into_iter().fold(f64::INFINITY, f64::min));
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+pub fn max(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = numbers("input", input).map(|n| n.into_iter().fold(f64::NEG_INFINITY, f64::max));
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+pub fn subtract(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = operands(input).map(|(a, b)| a - b);
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+pub fn divide(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = operands(input).and_then(|(a, b)| {
+        if b == 0.0 {
+            Err("division by zero".into())
+        } else {
+            Ok(a / b)
+        }
+    });
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+pub fn modulo(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = operands(input).and_then(|(a, b)| {
+        if b == 0.0 {
+            Err("modulo by zero".into())
+        } else {
+            Ok(a % b)
+        }
+    });
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+pub fn abs(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = number("input", input.slot_or("input", &Json::Null)).map(f64::abs);
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+pub fn round(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let zero = json!(0);
+
+    let out = number("input", input.slot_or("input", &Json::Null)).and_then(|v| {
+        let decimals = number("decimals", input.slot_or("decimals", &zero))?;
+        let factor = 10f64.powi(decimals as i32);
+
+        Ok((v * factor).round() / factor)
+    });
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+pub fn clamp(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = number("input", input.slot_or("input", &Json::Null)).and_then(|v| {
+        let min = number("min", input.slot_or("min", &Json::Null))?;
+        let max = number("max", input.slot_or("max", &Json::Null))?;
+
+        if min > max {
+            return Err(format!("min {min} is larger than max {max}"));
+        }
+
+        Ok(v.clamp(min, max))
+    });
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+/// Linear map a value from the range `in_min..in_max` to the range `out_min..out_max`
+pub fn scale(input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = number("input", input.slot_or("input", &Json::Null)).and_then(|v| {
+        let in_min = number("in_min", input.slot_or("in_min", &Json::Null))?;
+        let in_max = number("in_max", input.slot_or("in_max", &Json::Null))?;
+        let out_min = number("out_min", input.slot_or("out_min", &Json::Null))?;
+        let out_max = number("out_max", input.slot_or("out_max", &Json::Null))?;
+
+        if in_min == in_max {
+            return Err("input range is empty".into());
+        }
+
+        Ok(out_min + (v - in_min) * (out_max - out_min) / (in_max - in_min))
+    });
+
+    math_result(output, out);
+
+    Ok(())
+}
+
+/// Turn a node property in seconds into a duration, invalid durations are treated as zero
+fn seconds(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or_default()
+}
+
+pub struct Delay {
+    duration: Duration,
+    last: Option<Json>,
+    pending: VecDeque<(Instant, Json)>,
+    current: Json,
+}
+
+impl Delay {
+    pub fn new(secs: f64) -> Delay {
+        Delay {
+            duration: seconds(secs),
+            last: None,
+            pending: VecDeque::new(),
+            current: Json::Null,
+        }
+    }
+}
+
+/// Emit every change on the input after a fixed delay
+pub fn delay(state: &mut Delay, input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let now = input.now();
+    let value = input.slot_or("input", &Json::Null);
+
+    if state.last.as_ref() != Some(value) {
+        state.last = Some(value.clone());
+        state
+            .pending
+            .push_back((now + state.duration, value.clone()));
+    }
+
+    while let Some((at, _)) = state.pending.front() {
+        if *at > now {
+            break;
+        }
+
+        if let Some((_, value)) = state.pending.pop_front() {
+            state.current = value;
+        }
+    }
+
+    if let Some((at, _)) = state.pending.front() {
+        output.wake_at(*at);
+    }
+
+    output.slot("result", state.current.clone());
+
+    Ok(())
+}
+
+pub struct Debounce {
+    duration: Duration,
+    last: Option<(Instant, Json)>,
+    current: Json,
+}
+
+impl Debounce {
+    pub fn new(secs: f64) -> Debounce {
+        Debounce {
+            duration: seconds(secs),
+            last: None,
+            current: Json::Null,
+        }
+    }
+}
+
+/// Only let a value through once the input has been stable for the duration
+pub fn debounce(state: &mut Debounce, input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let now = input.now();
+    let value = input.slot_or("input", &Json::Null);
+
+    match &state.last {
+        Some((_, last)) if last == value => {}
+        _ => state.last = Some((now, value.clone())),
+    }
+
+    if let Some((changed, value)) = &state.last {
+        let stable_at = *changed + state.duration;
+
+        if stable_at <= now {
+            state.current = value.clone();
+        } else {
+            output.wake_at(stable_at);
+        }
+    }
+
+    output.slot("result", state.current.clone());
+
+    Ok(())
+}
+
+pub struct Throttle {
+    duration: Duration,
+    emitted: Option<Instant>,
+    current: Json,
+}
+
+impl Throttle {
+    pub fn new(secs: f64) -> Throttle {
+        Throttle {
+            duration: seconds(secs),
+            emitted: None,
+            current: Json::Null,
+        }
+    }
+}
+
+/// Let changes through at most once per duration, the latest value wins
+pub fn throttle(state: &mut Throttle, input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let now = input.now();
+    let value = input.slot_or("input", &Json::Null);
+
+    if &state.current != value {
+        match state.emitted {
+            Some(at) if at + state.duration > now => output.wake_at(at + state.duration),
+            _ => {
+                state.current = value.clone();
+                state.emitted = Some(now);
+            }
+        }
+    }
+
+    output.slot("result", state.current.clone());
+
+    Ok(())
+}
+
+pub struct Timeout {
+    duration: Duration,
+    high: bool,
+    until: Option<Instant>,
+}
+
+impl Timeout {
+    pub fn new(secs: f64) -> Timeout {
+        Timeout {
+            duration: seconds(secs),
+            high: false,
+            until: None,
+        }
+    }
+}
+
+/// Stays true while the input is true and for the duration after it went false,
+/// a motion sensor into this turns a light off a while after the last motion
+pub fn timeout(state: &mut Timeout, input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let now = input.now();
+    let high = matches!(input.slot_or("input", &Json::Null), Json::Bool(true));
+
+    if high {
+        state.until = None;
+    } else if state.high {
+        // Falling edge, start counting
+        state.until = Some(now + state.duration);
+    }
+
+    state.high = high;
+
+    let out = match state.until {
+        _ if high => true,
+        Some(until) if until > now => {
+            output.wake_at(until);
+            true
+        }
+        _ => {
+            state.until = None;
+            false
+        }
+    };
+
+    output.slot("result", json!(out));
+
+    Ok(())
+}
+
+/// True while any of the schedule rules match the wall clock
+pub fn schedule(schedule: &Schedule, input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let now = input.now_utc();
+
+    output.slot("result", json!(schedule.active_at(now)));
+
+    // Rules have minute resolution, look again at the start of the next minute
+    let into_minute = Duration::new(now.second() as u64, now.nanosecond());
+    output.wake_at(input.now() + Duration::from_secs(60) - into_minute);
+
+    Ok(())
+}
+
+pub struct Fresh {
+    id: ValueId,
+    max_age: Duration,
+}
+
+impl Fresh {
+    pub fn new(id: ValueId, secs: f64) -> Fresh {
+        Fresh {
+            id,
+            max_age: seconds(secs),
+        }
+    }
+}
+
+/// True while the value has been reported within the max age and is not an error
+pub fn fresh(state: &Fresh, input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let age = input
+        .updated(&state.id)
+        .and_then(|at| Duration::try_from(input.now_utc() - at).ok());
+
+    let out = match age {
+        Some(age) if age < state.max_age => {
+            // Check again when the value would turn stale
+            output.wake_at(input.now() + (state.max_age - age));
+            true
+        }
+        _ => false,
+    };
+
+    output.slot("result", json!(out));
+
+    Ok(())
+}
+
+fn object_result(output: &mut Outputs, result: Result<Json, String>) {
+    match result {
+        Ok(v) => {
+            output.slot("result", v);
+            output.slot("error", Json::Null);
+        }
+        Err(e) => {
+            output.slot("result", Json::Null);
+            output.slot("error", json!(e));
+        }
+    }
+}
+
+/// Read a field of the object on `input`, null if it is missing
+pub fn get_field(field: &String, input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let out = match input.slot_or("input", &Json::Null) {
+        Json::Null => Ok(Json::Null),
+        object @ Json::Object(_) => {
+            let value = if field.starts_with('/') {
+                object.pointer(field)
+            } else {
+                object.get(field)
+            };
+
+            Ok(value.cloned().unwrap_or(Json::Null))
+        }
+        other => Err(format!("input {other} is not an object")),
+    };
+
+    object_result(output, out);
+
+    Ok(())
+}
+
+/// Replace a field of the object on `input` with `value`, without an input object a new one is made
+pub fn set_field(field: &String, input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let value = input.slot_or("value", &Json::Null).clone();
+
+    let out = match input.slot_or("input", &Json::Null) {
+        Json::Null => Ok(json!({})),
+        object @ Json::Object(_) => Ok(object.clone()),
+        other => Err(format!("input {other} is not an object")),
+    }
+    .and_then(|mut object| {
+        let path: Vec<String> = match field.strip_prefix('/') {
+            Some(pointer) => pointer
+                .split('/')
+                .map(|key| key.replace("~1", "/").replace("~0", "~"))
+                .collect(),
+            None => vec![field.clone()],
+        };
+
+        let mut at = &mut object;
+
+        for key in &path {
+            if at.is_null() {
+                *at = json!({});
+            }
+
+            let Json::Object(inner) = at else {
+                return Err(format!("{field} does not point into an object"));
+            };
+
+            at = inner.entry(key.clone()).or_insert(Json::Null);
+        }
+
+        *at = value;
+
+        Ok(object)
+    });
+
+    object_result(output, out);
+
+    Ok(())
+}
+
+/// Make an object of the inputs named after its fields, fields without a value are left out
+#[allow(clippy::ptr_arg)] // Node functions get a reference to their data
+pub fn build_object(fields: &Vec<String>, input: &Inputs, output: &mut Outputs) -> Result<()> {
+    let object = fields
+        .iter()
+        .filter_map(|field| {
+            let value = input.slot_or(field.as_str(), &Json::Null);
+
+            (!value.is_null()).then(|| (field.clone(), value.clone()))
+        })
+        .collect();
+
+    output.slot("result", Json::Object(object));
+
+    Ok(())
+}
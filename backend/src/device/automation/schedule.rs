@@ -0,0 +1,286 @@
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, Time, UtcOffset, Weekday};
+
+/// A set of rules, the schedule is active when any of the rules are
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    pub rules: Vec<Rule>,
+    /// Offset from UTC in minutes the rules are written in. It is fixed, so rules
+    /// shift by an hour against local time when daylight saving time starts or ends
+    #[serde(default)]
+    pub utc_offset: i32,
+}
+
+impl Schedule {
+    pub fn active_at(&self, at: OffsetDateTime) -> bool {
+        let offset = UtcOffset::from_whole_seconds(self.utc_offset * 60).unwrap_or(UtcOffset::UTC);
+        let local = at.to_offset(offset);
+
+        self.rules.iter().any(|r| r.active_at(local))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum Rule {
+    /// Active between two times of day on the listed days,
+    /// if `to` is before `from` the window ends on the next day
+    #[serde(rename = "weekly")]
+    Weekly {
+        days: Vec<Day>,
+        from: TimeOfDay,
+        to: TimeOfDay,
+    },
+    /// Active for a number of minutes every time the cron expression matches
+    #[serde(rename = "cron")]
+    Cron {
+        expression: Cron,
+        #[serde(default = "one_minute")]
+        minutes: u32,
+    },
+}
+
+fn one_minute() -> u32 {
+    1
+}
+
+impl Rule {
+    fn active_at(&self, local: OffsetDateTime) -> bool {
+        match self {
+            Rule::Weekly { days, from, to } => {
+                let time = local.time();
+                let today = days.iter().any(|d| d.is(local.weekday()));
+                let yesterday = days.iter().any(|d| d.is(local.weekday().previous()));
+
+                if from.0 <= to.0 {
+                    today && time >= from.0 && time < to.0
+                } else {
+                    // Window wraps past midnight
+                    (today && time >= from.0) || (yesterday && time < to.0)
+                }
+            }
+            Rule::Cron {
+                expression,
+                minutes,
+            } => (0..(*minutes).clamp(1, 24 * 60))
+                .any(|m| expression.matches(local - time::Duration::minutes(m as i64))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Day {
+    fn is(&self, weekday: Weekday) -> bool {
+        let day = match weekday {
+            Weekday::Monday => Day::Mon,
+            Weekday::Tuesday => Day::Tue,
+            Weekday::Wednesday => Day::Wed,
+            Weekday::Thursday => Day::Thu,
+            Weekday::Friday => Day::Fri,
+            Weekday::Saturday => Day::Sat,
+            Weekday::Sunday => Day::Sun,
+        };
+
+        *self == day
+    }
+}
+
+/// A time of day written as `HH:MM`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(Time);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let (h, m) = value
+            .split_once(':')
+            .with_context(|| format!("{value} is not a time of day, expected HH:MM"))?;
+
+        let time = Time::from_hms(h.trim().parse()?, m.trim().parse()?, 0)?;
+
+        Ok(TimeOfDay(time))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(value: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", value.0.hour(), value.0.minute())
+    }
+}
+
+/// A standard five field cron expression, `minute hour day-of-month month day-of-week`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cron {
+    source: String,
+    minute: u64,
+    hour: u64,
+    day: u64,
+    month: u64,
+    weekday: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn matches(&self, at: OffsetDateTime) -> bool {
+        let bit = |set: u64, n: u8| set & (1 << n) != 0;
+
+        let day = bit(self.day, at.day());
+        let weekday = bit(self.weekday, at.weekday().number_days_from_sunday());
+
+        // Like cron, if both day fields are restricted either of them matching is enough
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        };
+
+        bit(self.minute, at.minute())
+            && bit(self.hour, at.hour())
+            && bit(self.month, at.month() as u8)
+            && day_matches
+    }
+}
+
+impl TryFrom<String> for Cron {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+
+        ensure!(
+            fields.len() == 5,
+            "cron expression {source:?} needs 5 fields, got {}",
+            fields.len()
+        );
+
+        let mut weekday = cron_field(fields[4], 0, 7)?;
+
+        // Both 0 and 7 is sunday
+        if weekday & (1 << 7) != 0 {
+            weekday |= 1;
+        }
+
+        Ok(Cron {
+            minute: cron_field(fields[0], 0, 59)?,
+            hour: cron_field(fields[1], 0, 23)?,
+            day: cron_field(fields[2], 1, 31)?,
+            month: cron_field(fields[3], 1, 12)?,
+            weekday,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+            source,
+        })
+    }
+}
+
+impl From<Cron> for String {
+    fn from(value: Cron) -> Self {
+        value.source
+    }
+}
+
+/// Parse one cron field into a bit set, supports `*`, `a`, `a-b`, `*/n`, `a-b/n` and lists of those
+fn cron_field(field: &str, min: u8, max: u8) -> Result<u64> {
+    let mut set = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u8>()?),
+            None => (part, 1),
+        };
+
+        ensure!(step > 0, "cron step can not be zero in {field:?}");
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            r => match r.split_once('-') {
+                Some((a, b)) => (a.parse()?, b.parse()?),
+                None => {
+                    let n = r.parse()?;
+                    // A single value with a step runs to the end of the range
+                    (n, if step > 1 { max } else { n })
+                }
+            },
+        };
+
+        ensure!(
+            min <= start && start <= end && end <= max,
+            "cron field {field:?} is out of range {min}-{max}"
+        );
+
+        for n in (start..=end).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+
+    Ok(set)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use time::macros::datetime;
+
+    #[test]
+    fn weekly_rules() {
+        let schedule: Schedule = serde_json::from_value(json!({
+            "rules": [
+                { "type": "weekly", "days": ["mon", "tue", "wed", "thu", "fri"], "from": "06:30", "to": "08:00" },
+                { "type": "weekly", "days": ["sat"], "from": "23:00", "to": "01:00" },
+            ],
+        }))
+        .unwrap();
+
+        // 2023-03-06 is a monday
+        assert!(!schedule.active_at(datetime!(2023-03-06 06:29 UTC)));
+        assert!(schedule.active_at(datetime!(2023-03-06 06:30 UTC)));
+        assert!(!schedule.active_at(datetime!(2023-03-06 08:00 UTC)));
+        assert!(!schedule.active_at(datetime!(2023-03-11 06:30 UTC)));
+
+        // Wraps past midnight into sunday
+        assert!(schedule.active_at(datetime!(2023-03-11 23:30 UTC)));
+        assert!(schedule.active_at(datetime!(2023-03-12 00:30 UTC)));
+        assert!(!schedule.active_at(datetime!(2023-03-12 23:30 UTC)));
+    }
+
+    #[test]
+    fn cron_rules() {
+        let schedule: Schedule = serde_json::from_value(json!({
+            "rules": [{ "type": "cron", "expression": "30 6 * * 1-5", "minutes": 90 }],
+            "utc_offset": 60,
+        }))
+        .unwrap();
+
+        assert!(schedule.active_at(datetime!(2023-03-06 05:30 UTC)));
+        assert!(schedule.active_at(datetime!(2023-03-06 06:59 UTC)));
+        assert!(!schedule.active_at(datetime!(2023-03-06 07:00 UTC)));
+        assert!(!schedule.active_at(datetime!(2023-03-12 05:30 UTC)));
+
+        let every: Cron = String::from("*/15 * * * 0,7").try_into().unwrap();
+
+        assert!(every.matches(datetime!(2023-03-12 10:45 UTC)));
+        assert!(!every.matches(datetime!(2023-03-12 10:46 UTC)));
+        assert!(!every.matches(datetime!(2023-03-13 10:45 UTC)));
+
+        assert!(Cron::try_from(String::from("* * *")).is_err());
+        assert!(Cron::try_from(String::from("61 * * * *")).is_err());
+    }
+}
@@ -3,7 +3,7 @@ use futures::Stream;
 use serde_derive::{Deserialize, Serialize};
 use sqlx::{sqlite::SqliteRow, types::Json, Row, SqliteConnection};
 
-use super::TaskSpec;
+use super::{Feature, TaskSpec, ValueKind};
 
 #[derive(Debug)]
 pub struct Device {
@@ -74,16 +74,125 @@ impl Device {
         Ok(dev)
     }
 
+    /// Devices controlled by this device
+    pub fn children<'a>(
+        device_id: &'a str,
+        conn: &'a mut SqliteConnection,
+    ) -> impl Stream<Item = Result<Device, sqlx::Error>> + 'a {
+        sqlx::query(include_str!("../../sql/device_children.sql"))
+            .bind(device_id)
+            .try_map(|row: SqliteRow| {
+                let Json(task_spec): Json<TaskSpec> = row.try_get("task_spec")?;
+                let Json(device_type): Json<DeviceType> = row.try_get("type")?;
+
+                Ok(Device {
+                    id: row.try_get("id")?,
+                    name: row.try_get("name")?,
+                    parent: row.try_get("parent")?,
+                    device_type,
+                    task_spec,
+                })
+            })
+            .fetch(conn)
+    }
+
+    /// Delete the device, its features and children go with it
+    pub async fn delete(device_id: &str, conn: &mut SqliteConnection) -> Result<()> {
+        sqlx::query(include_str!("../../sql/device_delete.sql"))
+            .bind(device_id)
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn rename(device_id: &str, name: &str, conn: &mut SqliteConnection) -> Result<()> {
+        sqlx::query(include_str!("../../sql/device_rename.sql"))
+            .bind(device_id)
+            .bind(name)
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_parent(
+        device_id: &str,
+        parent: Option<&str>,
+        conn: &mut SqliteConnection,
+    ) -> Result<()> {
+        sqlx::query(include_str!("../../sql/device_set_parent.sql"))
+            .bind(device_id)
+            .bind(parent)
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
     /// High level call to create a generic device save to database and notify on the device bus that a device was added
     pub async fn create_generic(name: String, conn: &mut SqliteConnection) -> Result<Device> {
+        Device::create_virtual(name, VirtualType::Generic, conn).await
+    }
+
+    /// Create a button, pushing `true` to its feature is seen as a single press
+    pub async fn create_button(name: String, conn: &mut SqliteConnection) -> Result<Device> {
+        let device = Device::create_virtual(name, VirtualType::Button, conn).await?;
+
+        let meta = serde_json::json!({ "momentary": true });
+        let feature =
+            Feature::attach_virtual(&device.id, "Press".into(), ValueKind::Bool, meta, conn)
+                .await?;
+
+        crate::value::set_momentary(crate::value::ValueId::new(&device.id, &feature.id));
+
+        Ok(device)
+    }
+
+    /// Create a slider with a number feature limited to `min..=max`
+    pub async fn create_slider(
+        name: String,
+        min: f64,
+        max: f64,
+        step: Option<f64>,
+        conn: &mut SqliteConnection,
+    ) -> Result<Device> {
+        anyhow::ensure!(min < max, "min {min} has to be smaller than max {max}");
+
+        let device = Device::create_virtual(name, VirtualType::Slider, conn).await?;
+
+        let mut meta = serde_json::json!({ "min": min, "max": max });
+
+        if let Some(step) = step {
+            meta["step"] = serde_json::json!(step);
+        }
+
+        Feature::attach_virtual(&device.id, "Level".into(), ValueKind::Number, meta, conn).await?;
+
+        Ok(device)
+    }
+
+    /// Create a toggle with a single on/off feature
+    pub async fn create_toggle(name: String, conn: &mut SqliteConnection) -> Result<Device> {
+        let device = Device::create_virtual(name, VirtualType::Toggle, conn).await?;
+
+        let meta = serde_json::json!({});
+        Feature::attach_virtual(&device.id, "State".into(), ValueKind::Bool, meta, conn).await?;
+
+        Ok(device)
+    }
+
+    async fn create_virtual(
+        name: String,
+        vty: VirtualType,
+        conn: &mut SqliteConnection,
+    ) -> Result<Device> {
         let id = super::random_id("virtual");
 
         let device = Device {
             id,
             name,
-            device_type: DeviceType::Virtual {
-                vty: VirtualType::Generic,
-            },
+            device_type: DeviceType::Virtual { vty },
             parent: None,
             task_spec: TaskSpec::NoOp,
         };
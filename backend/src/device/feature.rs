@@ -109,13 +109,66 @@ impl Feature {
             .fetch(conn)
     }
 
+    /// Remember the last value of a feature so it can be restored on startup,
+    /// this only applies to virtual features and features that can be written
+    pub async fn store_value(
+        device_id: &str,
+        feature_id: &str,
+        value: &Json,
+        conn: &mut SqliteConnection,
+    ) -> Result<()> {
+        sqlx::query(include_str!("../../sql/feature_store_value.sql"))
+            .bind(device_id)
+            .bind(feature_id)
+            .bind(SqlJson(value))
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    pub fn load_values(
+        conn: &mut SqliteConnection,
+    ) -> impl Stream<Item = Result<(String, String, Json), sqlx::Error>> + '_ {
+        sqlx::query(include_str!("../../sql/feature_last_values.sql"))
+            .try_map(|row: SqliteRow| {
+                let value: SqlJson<Json> = row.try_get("last_value")?;
+                Ok((row.try_get("device")?, row.try_get("id")?, value.0))
+            })
+            .fetch(conn)
+    }
+
+    /// All features that are pulsed by pushing to them, `momentary` is set in meta
+    pub fn load_momentary(
+        conn: &mut SqliteConnection,
+    ) -> impl Stream<Item = Result<(String, String), sqlx::Error>> + '_ {
+        sqlx::query(include_str!("../../sql/feature_momentary.sql"))
+            .try_map(|row: SqliteRow| Ok((row.try_get("device")?, row.try_get("id")?)))
+            .fetch(conn)
+    }
+
+    /// All features that have `stale_after` in seconds set in meta
+    pub fn load_stale_after(
+        conn: &mut SqliteConnection,
+    ) -> impl Stream<Item = Result<(String, String, f64), sqlx::Error>> + '_ {
+        sqlx::query(include_str!("../../sql/feature_stale_after.sql"))
+            .try_map(|row: SqliteRow| {
+                Ok((
+                    row.try_get("device")?,
+                    row.try_get("id")?,
+                    row.try_get("stale_after")?,
+                ))
+            })
+            .fetch(conn)
+    }
+
     /// Save a value spec
     pub async fn save(&self, device_id: &str, conn: &mut SqliteConnection) -> Result<()> {
         sqlx::query(include_str!("../../sql/feature_insert.sql"))
             .bind(device_id)
             .bind(&self.id)
             .bind(&self.name)
-            .bind(&self.virt)
+            .bind(self.virt)
             .bind(self.direction as u8)
             .bind(self.kind as u8)
             .bind(SqlJson(&self.meta))
@@ -126,6 +179,69 @@ impl Feature {
         Ok(())
     }
 
+    /// Replace or with `None` remove the automation of a feature
+    pub async fn set_automate(
+        device_id: &str,
+        feature_id: &str,
+        automate: Option<&Automation>,
+        conn: &mut SqliteConnection,
+    ) -> Result<()> {
+        sqlx::query(include_str!("../../sql/feature_set_automate.sql"))
+            .bind(device_id)
+            .bind(feature_id)
+            .bind(automate.map(SqlJson))
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(
+        device_id: &str,
+        feature_id: &str,
+        conn: &mut SqliteConnection,
+    ) -> Result<()> {
+        sqlx::query(include_str!("../../sql/feature_delete.sql"))
+            .bind(device_id)
+            .bind(feature_id)
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn rename(
+        device_id: &str,
+        feature_id: &str,
+        name: &str,
+        conn: &mut SqliteConnection,
+    ) -> Result<()> {
+        sqlx::query(include_str!("../../sql/feature_rename.sql"))
+            .bind(device_id)
+            .bind(feature_id)
+            .bind(name)
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remove the non virtual features of a device that are not in `keep`, returns the removed ids
+    pub async fn delete_missing(
+        device_id: &str,
+        keep: &[&str],
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<String>> {
+        let deleted = sqlx::query(include_str!("../../sql/feature_delete_missing.sql"))
+            .bind(device_id)
+            .bind(SqlJson(keep))
+            .try_map(|row: SqliteRow| row.try_get("id"))
+            .fetch_all(conn)
+            .await?;
+
+        Ok(deleted)
+    }
+
     /// High level API to attach a Virtual feature to a device
     pub async fn attach_virtual(
         device_id: &str,
@@ -151,43 +267,151 @@ impl Feature {
         Ok(feature)
     }
 
+    /// Pick the value of this feature out of a payload using a JSON pointer, translate
+    /// binary values with `value_on` and `value_off` and validate the result
+    pub fn extract(&self, payload: &Json, pointer: &str) -> Result<Json, String> {
+        let Some(mut value) = payload.pointer(pointer) else {
+            return Err(format!("Invalid JSON pointer {}\n{:#?}", pointer, payload));
+        };
+
+        // Rewrite binary values to a boolean before we validate
+        // if the base value is not already a boolean
+        if self.kind == ValueKind::Bool && !value.is_boolean() {
+            let Some(on) = self.meta.get("value_on") else {
+                return Err("meta value 'value_on' required for binary device".into());
+            };
+
+            let Some(off) = self.meta.get("value_off") else {
+                return Err("meta value 'value_off' required for binary device".into());
+            };
+
+            if value == on {
+                value = &Json::Bool(true)
+            } else if value == off {
+                value = &Json::Bool(false)
+            }
+        }
+
+        self.validate(value)
+    }
+
+    /// Turn a value into what the device expects, the reverse of [`Feature::extract`].
+    /// None for booleans without `value_on` and `value_off` in meta, we do not know what to send
+    pub fn to_wire(&self, value: Json) -> Option<Json> {
+        if self.kind != ValueKind::Bool {
+            return Some(value);
+        }
+
+        let key = if value == Json::Bool(true) {
+            "value_on"
+        } else {
+            "value_off"
+        };
+
+        self.meta.get(key).cloned()
+    }
+
+    /// The payload that writes a value, nested where `pointer` reads it from
+    pub fn to_payload(&self, pointer: &str, value: Json) -> Option<Json> {
+        Some(nest_at(pointer, self.to_wire(value)?))
+    }
+
     /// Validate a if [`Value`] is Valid for this Feature
     pub fn validate(&self, value: &Json) -> Result<Json, String> {
-        let possible: Vec<String> = self
-            .meta
-            .get("possible")
-            .map(|v| serde_json::from_value(v.clone()))
-            .and_then(|s| s.ok())
-            .unwrap_or(vec![]);
-
-        match (value, self.kind) {
-            (Json::Null, _) => Ok(Json::Null),
-            (Json::Bool(b), ValueKind::Bool) => Ok(Json::Bool(*b)),
-            (Json::Number(n), ValueKind::Number { .. }) => Ok(Json::Number(n.clone())),
-            (Json::String(s), ValueKind::String) => Ok(Json::String(s.clone())),
-
-            (Json::String(s), ValueKind::State) => {
-                if s.is_empty() {
-                    // Treat empty strings a null, quite a few devices go back to an "empty", state
-                    Ok(Json::Null)
-                } else if possible.contains(s) {
-                    Ok(Json::String(s.clone()))
-                } else {
-                    Err(format!("{} is not part of state set {:?}", s, possible))
-                }
+        check(value, self.kind, &self.meta)
+    }
+}
+
+/// Check a value against a kind and its meta, objects check the schema of their
+/// `fields` and arrays the schema of their `items`, a schema is a meta object with a `kind`
+///
+/// `{"fields": {"x": {"kind": "Number"}, "y": {"kind": "Number"}}}`
+fn check(value: &Json, kind: ValueKind, meta: &Json) -> Result<Json, String> {
+    let possible: Vec<String> = meta
+        .get("possible")
+        .map(|v| serde_json::from_value(v.clone()))
+        .and_then(|s| s.ok())
+        .unwrap_or(vec![]);
+
+    match (value, kind) {
+        (Json::Null, _) => Ok(Json::Null),
+        (Json::Bool(b), ValueKind::Bool) => Ok(Json::Bool(*b)),
+        (Json::Number(n), ValueKind::Number) => Ok(Json::Number(n.clone())),
+        (Json::String(s), ValueKind::String) => Ok(Json::String(s.clone())),
+
+        (Json::String(s), ValueKind::State) => {
+            if s.is_empty() {
+                // Treat empty strings a null, quite a few devices go back to an "empty", state
+                Ok(Json::Null)
+            } else if possible.contains(s) {
+                Ok(Json::String(s.clone()))
+            } else {
+                Err(format!("{} is not part of state set {:?}", s, possible))
             }
+        }
+
+        (Json::Array(items), ValueKind::Array) => match meta.get("items") {
+            Some(schema) => items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| check_schema(item, schema).map_err(|e| format!("[{i}] {e}")))
+                .collect::<Result<_, _>>()
+                .map(Json::Array),
+            None => Ok(Json::Array(items.clone())),
+        },
 
-            (Json::Array(_), _) => Err("Only descrete json values allowed, got array".into()),
-            (Json::Object(_), _) => Err("Only descrete json values allowed, got array".into()),
+        (Json::Object(object), ValueKind::Object) => {
+            let fields = meta.get("fields").and_then(|f| f.as_object());
 
-            (a, b) => Err(format!(
-                "Got value of {:?} expected value of kind {:?}",
-                a, b
-            )),
+            // Fields without a schema are passed on, devices like to report more than they expose
+            object
+                .iter()
+                .map(|(key, value)| match fields.and_then(|f| f.get(key)) {
+                    Some(schema) => check_schema(value, schema)
+                        .map(|v| (key.clone(), v))
+                        .map_err(|e| format!("{key}: {e}")),
+                    None => Ok((key.clone(), value.clone())),
+                })
+                .collect::<Result<_, _>>()
+                .map(Json::Object)
         }
+
+        (Json::Array(_), kind) => Err(format!("Got an array, expected value of kind {kind:?}")),
+        (Json::Object(_), kind) => Err(format!("Got an object, expected value of kind {kind:?}")),
+
+        (a, b) => Err(format!(
+            "Got value of {:?} expected value of kind {:?}",
+            a, b
+        )),
     }
 }
 
+fn check_schema(value: &Json, schema: &Json) -> Result<Json, String> {
+    let kind = schema
+        .get("kind")
+        .map(|k| serde_json::from_value(k.clone()))
+        .and_then(|k| k.ok())
+        .ok_or_else(|| format!("schema {schema} has no valid kind"))?;
+
+    check(value, kind, schema)
+}
+
+/// Put a value in nested objects so it ends up where `pointer` would find it
+fn nest_at(pointer: &str, value: Json) -> Json {
+    pointer
+        .split('/')
+        .skip(1)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .fold(value, |inner, key| {
+            let key = key.replace("~1", "/").replace("~0", "~");
+            let mut object = serde_json::Map::new();
+            object.insert(key, inner);
+            Json::Object(object)
+        })
+}
+
 #[derive(Debug, Eq, PartialEq, Copy, Clone, sqlx::Type, Enum)]
 #[repr(u8)]
 pub enum ValueDirection {
@@ -210,7 +434,6 @@ impl TryFrom<u8> for ValueDirection {
 }
 
 impl ValueDirection {
-    #[allow(unused)]
     pub fn can_read(&self) -> bool {
         match self {
             ValueDirection::Source => true,
@@ -227,6 +450,10 @@ pub enum ValueKind {
     Number = 1,
     State = 2,
     String = 3,
+    /// Several values in one, like the color of a light
+    Object = 4,
+    /// A list of values, like a heating schedule
+    Array = 5,
 }
 
 impl TryFrom<u8> for ValueKind {
@@ -238,6 +465,8 @@ impl TryFrom<u8> for ValueKind {
             1 => ValueKind::Number,
             2 => ValueKind::State,
             3 => ValueKind::String,
+            4 => ValueKind::Object,
+            5 => ValueKind::Array,
             _ => anyhow::bail!("value {} is not a valid ValueDirection", value),
         })
     }
@@ -1,22 +1,28 @@
 mod automation;
+#[allow(clippy::module_inception)]
 mod device;
 mod feature;
 mod sun;
 mod task_spec;
 
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
 use anyhow::Result;
 use futures::{Stream, StreamExt, TryStreamExt};
-use serde_json::json;
-use time::{Duration, OffsetDateTime};
-use tracing::debug;
+use serde_json::{json, Value as Json};
+use sqlx::SqliteConnection;
+use time::{Duration, OffsetDateTime, UtcOffset};
+use tracing::{error, warn};
 
 use crate::{
     db,
     program::Program,
-    task::Task,
-    topic::static_topic,
+    strings::IString,
+    task::{RestartPolicy, Task},
+    topic::{static_topic, Lagged},
     value::{self, ValueId},
 };
 pub use automation::Automation;
@@ -26,7 +32,10 @@ pub use task_spec::*;
 
 use self::sun::SunPhase;
 
+const PERSIST_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
 static_topic!(CHANGED, Arc<Device>);
+static_topic!(REMOVED, Arc<Device>);
 
 pub fn notify_changed<T>(device: T)
 where
@@ -39,6 +48,18 @@ pub fn changed() -> impl Stream<Item = Arc<Device>> {
     CHANGED.subscribe()
 }
 
+pub fn notify_removed<T>(device: T)
+where
+    T: Into<Arc<Device>>,
+{
+    REMOVED.publish(device.into());
+}
+
+/// Devices that have been deleted
+pub fn removed() -> impl Stream<Item = Arc<Device>> {
+    REMOVED.subscribe()
+}
+
 pub fn random_id(prefix: &str) -> String {
     let [a, b]: [u64; 2] = rand::random();
     format!("{prefix}:{a:x}{b:x}")
@@ -48,6 +69,17 @@ pub fn random_id(prefix: &str) -> String {
 pub async fn restore_task(task: Task) -> Result<()> {
     let mut conn = db::connection().await?;
 
+    // Load the last known values before anything starts to react to them
+    restore_values(&mut conn).await?;
+
+    {
+        let mut momentary = Feature::load_momentary(&mut conn);
+
+        while let Some((device_id, feature_id)) = momentary.try_next().await? {
+            value::set_momentary(ValueId::new(&device_id, &feature_id));
+        }
+    }
+
     {
         let mut devices = Device::all(&mut conn);
         while let Some(device) = devices.try_next().await? {
@@ -67,80 +99,437 @@ pub async fn restore_task(task: Task) -> Result<()> {
     Ok(())
 }
 
+/// Put the persisted values back into storage
+async fn restore_values(conn: &mut SqliteConnection) -> Result<()> {
+    let mut values = Feature::load_values(conn);
+
+    while let Some((device_id, feature_id, value)) = values.try_next().await? {
+        value::restore(ValueId::new(&device_id, &feature_id), value);
+    }
+
+    Ok(())
+}
+
+/// Persist the last value of features so they survive a restart
+///
+/// Changes are collected and written in one transaction every `PERSIST_INTERVAL`,
+/// only the latest value of a feature is kept in between
+pub async fn persist_values_task(task: Task) -> Result<()> {
+    let mut values = task.until_stopped(value::subscribe_lagged());
+    let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+    let mut pending = HashMap::new();
+    // Pending values are written before the task lets itself be stopped
+    let _busy = task.busy();
+
+    loop {
+        tokio::select! {
+            next = values.next() => {
+                let updated = match next {
+                    Some(Ok(update)) => vec![update],
+                    // Whatever we missed is in there
+                    Some(Err(_)) => value::all(),
+                    None => break,
+                };
+
+                // Errors are not worth restoring
+                for (id, current) in updated {
+                    if let Ok(value) = current.value {
+                        pending.insert(id, value);
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                if !pending.is_empty() {
+                    flush_values(&mut pending).await;
+                }
+            }
+        }
+    }
+
+    flush_values(&mut pending).await;
+
+    Ok(())
+}
+
+/// Write pending values, on errors they are kept for the next attempt
+async fn flush_values(pending: &mut HashMap<ValueId, Json>) {
+    let stored = async {
+        let mut tx = db::begin().await?;
+        store_values(pending, &mut tx).await?;
+        tx.commit().await?;
+        anyhow::Ok(())
+    };
+
+    match stored.await {
+        Ok(()) => pending.clear(),
+        Err(e) => error!("Could not store {} values\n{e:?}", pending.len()),
+    }
+}
+
+async fn store_values(values: &HashMap<ValueId, Json>, conn: &mut SqliteConnection) -> Result<()> {
+    for (id, value) in values {
+        Feature::store_value(id.device.into(), id.feature.into(), value, conn).await?;
+    }
+
+    Ok(())
+}
+
+/// Turn values into errors when their feature has not reported within `stale_after` seconds
+///
+/// The timeouts are loaded once and again whenever a device changes
+pub async fn staleness_task(_: Task) -> Result<()> {
+    let started = OffsetDateTime::now_utc();
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+
+    let mut changed = changed();
+    let mut removed = removed();
+    let mut stale_after = load_stale_after().await?;
+
+    loop {
+        tokio::select! {
+            Some(_) = changed.next() => stale_after = load_stale_after().await?,
+            Some(_) = removed.next() => stale_after = load_stale_after().await?,
+            _ = interval.tick() => mark_stale(&stale_after, started, OffsetDateTime::now_utc()),
+        }
+    }
+}
+
+async fn load_stale_after() -> Result<HashMap<ValueId, f64>> {
+    let mut conn = db::connection().await?;
+
+    Feature::load_stale_after(&mut conn)
+        .map_ok(|(device_id, feature_id, stale_after)| {
+            (ValueId::new(&device_id, &feature_id), stale_after)
+        })
+        .try_collect()
+        .await
+        .map_err(Into::into)
+}
+
+/// Values we have not heard from since `started` get the benefit of the doubt
+fn mark_stale(stale_after: &HashMap<ValueId, f64>, started: OffsetDateTime, now: OffsetDateTime) {
+    for (&id, &stale_after) in stale_after {
+        let last_updated = {
+            let current = value::current(id);
+
+            if current.value.is_err() {
+                // Already an error, stale or not
+                continue;
+            }
+
+            current.last_updated.unwrap_or(started)
+        };
+
+        if (now - last_updated).as_seconds_f64() > stale_after {
+            value::set_stale(
+                id,
+                format!("Unavailable, no update in the last {stale_after} seconds"),
+            );
+        }
+    }
+}
+
+/// The label of the task `spawn_device_tasks` runs for a device, if it needs one
+pub fn device_task_label(device: &Device) -> Option<String> {
+    let label = match &device.task_spec {
+        TaskSpec::Zigbee2Mqtt(server) => format!("zigbee2mqtt:{}:{}", server.host, server.port),
+        TaskSpec::Zigbee2MqttDevice(_) => format!("{}/Zigbee2MqttDevice", device.id),
+        TaskSpec::Zigbee2MqttGroup(_) => format!("{}/Zigbee2MqttGroup", device.id),
+        TaskSpec::HomeAssistant { server, .. } => {
+            format!("homeassistant:{}:{}", server.host, server.port)
+        }
+        TaskSpec::HomeAssistantExport { server, .. } => {
+            format!("homeassistant_export:{}:{}", server.host, server.port)
+        }
+        TaskSpec::Mqtt(_) => format!("{}/MqttDevice", device.id),
+        TaskSpec::Sun { .. } => "thesun".into(),
+        TaskSpec::Clock { .. } => "theclock".into(),
+        TaskSpec::NoOp => return None,
+    };
+
+    Some(label)
+}
+
 pub fn spawn_device_tasks(task: &Task, device: &Device) {
+    let Some(label) = device_task_label(device) else {
+        return;
+    };
+
+    // Integrations hold everything below them together, they should never stop on their own
+    let integration = RestartPolicy::always();
+    let device_policy = RestartPolicy::on_failure();
+
     match &device.task_spec {
         TaskSpec::Zigbee2Mqtt(server) => {
-            let label = format!("zigbee2mqtt:{}:{}", server.host, server.port);
-
             if task.has_task(&label) {
                 // There is no need to reboot the task just ignore
                 return;
             }
 
-            task.spawn_with_argument(
+            task.spawn_supervised(
                 label,
                 (device.id.clone(), server.clone()),
+                integration,
                 crate::integration::zigbee2mqtt::zigbee2mqtt_update,
             );
         }
-        TaskSpec::Zigbee2MqttDevice(_) => {
-            let label = format!("{}/Zigbee2MqttDevice", device.id);
-
-            task.spawn_with_argument(
+        TaskSpec::Zigbee2MqttDevice(_) | TaskSpec::Zigbee2MqttGroup(_) => {
+            task.spawn_supervised(
                 label,
-                (&device.id).into(),
+                IString::from(&device.id),
+                device_policy,
                 crate::integration::zigbee2mqtt::zigbee2mqtt_device,
             );
         }
-        TaskSpec::Sun { lat, lon } => task.spawn_with_argument("thesun", (*lat, *lon), the_sun),
+        TaskSpec::HomeAssistant { server, prefix } => {
+            if task.has_task(&label) {
+                return;
+            }
+
+            task.spawn_supervised(
+                label,
+                (device.id.clone(), server.clone(), prefix.clone()),
+                integration,
+                crate::integration::homeassistant::discovery_update,
+            );
+        }
+        TaskSpec::HomeAssistantExport {
+            server,
+            prefix,
+            topic,
+        } => {
+            task.spawn_supervised(
+                label,
+                (
+                    device.id.clone(),
+                    server.clone(),
+                    prefix.clone(),
+                    topic.clone(),
+                ),
+                integration,
+                crate::integration::homeassistant::export_update,
+            );
+        }
+        TaskSpec::Mqtt(_) => {
+            task.spawn_supervised(
+                label,
+                IString::from(&device.id),
+                device_policy,
+                crate::integration::mqtt::mqtt_device,
+            );
+        }
+        // Nothing outside the process can make these fail, restarting would not help
+        TaskSpec::Sun { lat, lon } => task.spawn_with_argument(label, (*lat, *lon), the_sun),
+        TaskSpec::Clock { utc_offset } => task.spawn_with_argument(label, *utc_offset, the_clock),
         TaskSpec::NoOp => {}
     }
 }
 
+/// The label of the task running the automation of a feature
+pub fn automation_label(target: ValueId) -> String {
+    format!("{:?}/{:?}/automate", target.device, target.feature)
+}
+
+/// Pause or resume the automation of a feature, the automation itself is kept
+pub async fn set_automation_enabled(task: &Task, target: ValueId, enabled: bool) -> Result<()> {
+    let mut conn = db::connection().await?;
+    let feature = Feature::load(target.device.into(), target.feature.into(), &mut conn).await?;
+
+    let Some(mut automation) = feature.automate else {
+        anyhow::bail!("{} has no automation", feature.name);
+    };
+
+    automation.enabled = enabled;
+
+    // Compile before saving so a broken automation is not enabled
+    spawn_automation_task(task, target, &automation)?;
+
+    Feature::set_automate(
+        target.device.into(),
+        target.feature.into(),
+        Some(&automation),
+        &mut conn,
+    )
+    .await
+}
+
+/// Stop and remove the automation of a feature, returns false if there was none
+pub async fn delete_automation(task: &Task, target: ValueId) -> Result<bool> {
+    let mut conn = db::connection().await?;
+    let feature = Feature::load(target.device.into(), target.feature.into(), &mut conn).await?;
+
+    task.forget(&automation_label(target));
+
+    Feature::set_automate(target.device.into(), target.feature.into(), None, &mut conn).await?;
+
+    Ok(feature.automate.is_some())
+}
+
+/// Delete a device, its features and the devices it controls and stop all their tasks
+pub async fn delete_device(task: &Task, device_id: &str) -> Result<()> {
+    let mut tx = db::begin().await?;
+    let removed = delete_device_tree(device_id, &mut tx).await?;
+    tx.commit().await?;
+
+    forget_devices(task, removed);
+
+    Ok(())
+}
+
+/// Delete a device from the database, returns it and its descendants with their features
+async fn delete_device_tree(
+    device_id: &str,
+    conn: &mut SqliteConnection,
+) -> Result<Vec<(Device, Vec<Feature>)>> {
+    // The children are removed by the database, their tasks we have to stop ourselves
+    let mut removed = vec![];
+    let mut stack = vec![Device::load_by_id(device_id, conn).await?];
+
+    while let Some(device) = stack.pop() {
+        let children: Vec<Device> = Device::children(&device.id, conn).try_collect().await?;
+        let features: Vec<Feature> = Feature::load_by_device(&device.id, conn)
+            .try_collect()
+            .await?;
+
+        stack.extend(children);
+        removed.push((device, features));
+    }
+
+    Device::delete(device_id, conn).await?;
+
+    Ok(removed)
+}
+
+/// Stop the tasks of deleted devices, drop their values and tell everyone they are gone
+fn forget_devices(task: &Task, removed: Vec<(Device, Vec<Feature>)>) {
+    for (device, features) in removed {
+        if let Some(label) = device_task_label(&device) {
+            task.forget(&label);
+        }
+
+        let ids: Vec<&str> = features.iter().map(|f| f.id.as_str()).collect();
+        forget_features(task, &device.id, &ids);
+
+        notify_removed(device);
+    }
+}
+
+/// Delete a single feature and restart the task of its device so it forgets about it
+pub async fn delete_feature(task: &Task, device_id: &str, feature_id: &str) -> Result<()> {
+    let mut conn = db::connection().await?;
+
+    let device = Device::load_by_id(device_id, &mut conn).await?;
+    Feature::delete(device_id, feature_id, &mut conn).await?;
+
+    forget_features(task, device_id, &[feature_id]);
+
+    // Integrations keep running, only the devices themselves care about their features
+    if !matches!(device.device_type, DeviceType::Integration { .. }) {
+        spawn_device_tasks(task, &device);
+    }
+
+    notify_changed(device);
+
+    Ok(())
+}
+
+/// Stop the automations of deleted features and drop their values
+pub fn forget_features<T: AsRef<str>>(task: &Task, device_id: &str, feature_ids: &[T]) {
+    for feature_id in feature_ids {
+        let id = ValueId::new(device_id, feature_id.as_ref());
+
+        task.forget(&automation_label(id));
+        value::forget(id);
+    }
+}
+
 pub fn spawn_automation_task(task: &Task, target: ValueId, automation: &Automation) -> Result<()> {
-    let package = automation.compile(target)?;
+    if !automation.enabled {
+        task.stop(&automation_label(target));
+        return Ok(());
+    }
 
-    let label = format!("{:?}/{:?}/automate", target.device, target.feature);
-    task.spawn_with_argument(label, package, automation_task);
+    // Compile here as well so a broken automation is reported to whoever set it
+    automation.compile(target)?;
+
+    task.spawn_supervised(
+        automation_label(target),
+        (target, automation.clone()),
+        RestartPolicy::on_failure(),
+        automation_task,
+    );
 
     Ok(())
 }
 
-async fn automation_task((mut program, deps): (Program, Vec<ValueId>), _: Task) -> Result<()> {
+async fn automation_task((target, automation): (ValueId, Automation), _: Task) -> Result<()> {
+    let (mut program, deps) = automation.compile(target)?;
+
     if program.steps() == 0 {
         // Program does not do anything, no need for us to run
         return Ok(());
     }
 
-    let mut vals = value::subscribe();
+    let mut vals = value::subscribe_values(&deps);
 
     // Fetch the current world view
-    let mut input = deps
-        .into_iter()
-        .map(|vid| {
-            let current = value::current(vid).value().clone().unwrap_or_default();
+    let mut input = BTreeMap::new();
 
-            (vid, current)
-        })
-        .collect();
+    for vid in deps {
+        let current = value::current(vid).clone();
 
-    // Execute once on the availiable data
-    for (k, v) in program.execute(&input)? {
-        // Push program outputs
-        value::push(k, v);
+        program.set_updated(vid, reported(&current));
+        input.insert(vid, current.value.unwrap_or_default());
     }
 
-    while let Some((key, value)) = vals.next().await {
-        // Make sure its a value we care about in this Automation
-        if input.contains_key(&key) {
-            // We keep track of the input values into the program away from the global value store
-            // to make sure we have stable values for the entire execution and so we dont miss an intermediate value
-            input.insert(key, value.unwrap_or_default());
+    // Execute once on the availiable data
+    run_program(&mut program, &input)?;
 
-            // Execute the program
-            for (k, v) in program.execute(&input)? {
-                // Push program outputs
-                value::push(k, v);
+    loop {
+        // Some nodes want to run again after some time even if no value changes
+        let wake = program.wake().map(tokio::time::Instant::from_std);
+
+        tokio::select! {
+            next = vals.next() => {
+                let (key, update) = match next {
+                    Some(Ok(next)) => next,
+                    Some(Err(Lagged(missed))) => {
+                        warn!("Automation of {target:?} missed {missed} values, catching up");
+
+                        // Whatever we missed, the store has the latest of it
+                        for (vid, value) in input.iter_mut() {
+                            let current = value::current(*vid).clone();
+
+                            program.set_updated(*vid, reported(&current));
+                            *value = current.value.unwrap_or_default();
+                        }
+
+                        run_program(&mut program, &input)?;
+                        continue;
+                    }
+                    None => break,
+                };
+
+                // We only subscribed to the inputs of this Automation
+                if let Some(value) = input.get_mut(&key) {
+                    // We keep track of the input values into the program away from the global value store
+                    // to make sure we have stable values for the entire execution and so we dont miss an intermediate value
+                    program.set_updated(key, reported(&update));
+                    *value = update.value.unwrap_or_default();
+
+                    run_program(&mut program, &input)?;
+                }
+            }
+            _ = tokio::time::sleep_until(wake.unwrap_or_else(tokio::time::Instant::now)), if wake.is_some() => {
+                // Reports that did not change a value are not published, take their time from the store
+                for vid in input.keys() {
+                    program.set_updated(*vid, reported(&value::current(*vid)));
+                }
+
+                // Only time passed, targets already have whatever did not change
+                for (k, v) in program.execute_changed(&input)? {
+                    value::push(k, v);
+                }
             }
         }
     }
@@ -148,6 +537,21 @@ async fn automation_task((mut program, deps): (Program, Vec<ValueId>), _: Task)
     Ok(())
 }
 
+/// When a value was last reported, None while it is an error
+fn reported(current: &value::Current) -> Option<OffsetDateTime> {
+    current.value.as_ref().ok().and(current.last_updated)
+}
+
+fn run_program(program: &mut Program, input: &BTreeMap<ValueId, Json>) -> Result<()> {
+    // Execute the program
+    for (k, v) in program.execute(input)? {
+        // Push program outputs
+        value::push(k, v);
+    }
+
+    Ok(())
+}
+
 async fn the_sun((lat, lon): (f64, f64), _: Task) -> Result<()> {
     let state_id = ValueId::new("thesun", "state");
     let up_id = ValueId::new("thesun", "up");
@@ -186,3 +590,268 @@ async fn the_sun((lat, lon): (f64, f64), _: Task) -> Result<()> {
         tokio::time::sleep(next.try_into()?).await;
     }
 }
+
+/// Publish the local time, utc_offset is in minutes
+async fn the_clock(utc_offset: i32, _: Task) -> Result<()> {
+    let hour_id = ValueId::new("theclock", "hour");
+    let minute_id = ValueId::new("theclock", "minute");
+    let weekday_id = ValueId::new("theclock", "weekday");
+    let weekend_id = ValueId::new("theclock", "is_weekend");
+    let date_id = ValueId::new("theclock", "date");
+
+    let offset = UtcOffset::from_whole_seconds(utc_offset * 60)?;
+
+    loop {
+        let now = OffsetDateTime::now_utc().to_offset(offset);
+        let weekday = now.weekday();
+
+        let is_weekend = matches!(weekday, time::Weekday::Saturday | time::Weekday::Sunday);
+        let date = format!("{}-{:02}-{:02}", now.year(), now.month() as u8, now.day());
+
+        value::set_current(hour_id, Ok(json!(now.hour())));
+        value::set_current(minute_id, Ok(json!(now.minute())));
+        value::set_current(weekday_id, Ok(json!(weekday.to_string().to_lowercase())));
+        value::set_current(weekend_id, Ok(json!(is_weekend)));
+        value::set_current(date_id, Ok(json!(date)));
+
+        // Sleep until the start of the next minute
+        let into_minute =
+            Duration::seconds(now.second() as i64) + Duration::nanoseconds(now.nanosecond() as i64);
+
+        tokio::time::sleep((Duration::MINUTE - into_minute).try_into()?).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+    use sqlx::{sqlite::SqlitePoolOptions, Connection, SqliteConnection};
+    use time::{Duration, OffsetDateTime};
+
+    use futures::StreamExt;
+
+    use super::{Device, DeviceType, Feature, TaskSpec, ValueDirection, ValueKind};
+    use crate::task::{create_group, Task};
+    use crate::value::{self, ValueId};
+
+    async fn database() -> SqliteConnection {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&mut conn).await.unwrap();
+
+        let device = Device {
+            id: "persisted".into(),
+            name: "Persisted".into(),
+            device_type: DeviceType::Hardware,
+            parent: None,
+            task_spec: TaskSpec::NoOp,
+        };
+
+        device.save(&mut conn).await.unwrap();
+
+        for (id, direction) in [
+            ("setpoint", ValueDirection::Sink),
+            ("temperature", ValueDirection::Source),
+        ] {
+            let feature = Feature {
+                id: id.into(),
+                name: id.into(),
+                virt: false,
+                direction,
+                kind: ValueKind::Number,
+                meta: json!({}),
+                automate: None,
+            };
+
+            feature.save("persisted", &mut conn).await.unwrap();
+        }
+
+        conn
+    }
+
+    #[tokio::test]
+    async fn restore_persisted_values() {
+        let mut conn = database().await;
+
+        let setpoint = ValueId::new("persisted", "setpoint");
+        let temperature = ValueId::new("persisted", "temperature");
+
+        let values = HashMap::from([(setpoint, json!(21)), (temperature, json!(19.5))]);
+        super::store_values(&values, &mut conn).await.unwrap();
+
+        super::restore_values(&mut conn).await.unwrap();
+
+        // Only features that can be written to are restored
+        assert_eq!(value::current(setpoint).value, Ok(json!(21)));
+        assert_eq!(value::current(temperature).value, Ok(json!(null)));
+    }
+
+    #[tokio::test]
+    async fn delete_missing_features() {
+        let mut conn = database().await;
+
+        let deleted = Feature::delete_missing("persisted", &["setpoint"], &mut conn)
+            .await
+            .unwrap();
+
+        assert_eq!(deleted, vec!["temperature".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn renames_survive_saving_again() {
+        let mut conn = database().await;
+
+        Device::rename("persisted", "Living room", &mut conn)
+            .await
+            .unwrap();
+        Feature::rename("persisted", "setpoint", "Target", &mut conn)
+            .await
+            .unwrap();
+
+        // What an integration does on its next sync
+        let device = Device::load_by_id("persisted", &mut conn).await.unwrap();
+        let device = Device {
+            name: "Persisted".into(),
+            ..device
+        };
+        device.save(&mut conn).await.unwrap();
+
+        let setpoint = Feature::load("persisted", "setpoint", &mut conn)
+            .await
+            .unwrap();
+        let setpoint = Feature {
+            name: "setpoint".into(),
+            ..setpoint
+        };
+        setpoint.save("persisted", &mut conn).await.unwrap();
+
+        let device = Device::load_by_id("persisted", &mut conn).await.unwrap();
+        assert_eq!(device.name, "Living room");
+
+        let setpoint = Feature::load("persisted", "setpoint", &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(setpoint.name, "Target");
+    }
+
+    #[test]
+    fn quiet_values_turn_stale() {
+        let reported = ValueId::new("stale", "reported");
+        let silent = ValueId::new("stale", "silent");
+        let stale_after = HashMap::from([(reported, 60.0), (silent, 60.0)]);
+
+        let started = OffsetDateTime::now_utc();
+        value::set_current(reported, Ok(json!(1)));
+        let last_updated = value::current(reported).last_updated;
+
+        super::mark_stale(&stale_after, started, started + Duration::seconds(30));
+        assert_eq!(value::current(reported).value, Ok(json!(1)));
+        assert_eq!(value::current(silent).value, Ok(json!(null)));
+
+        super::mark_stale(&stale_after, started, started + Duration::seconds(120));
+        let message = "Unavailable, no update in the last 60 seconds".to_string();
+        assert_eq!(value::current(reported).value, Err(message.clone()));
+        assert_eq!(value::current(silent).value, Err(message));
+
+        // Being stale is not a report
+        assert_eq!(value::current(reported).last_updated, last_updated);
+
+        value::set_current(reported, Ok(json!(1)));
+        assert_eq!(value::current(reported).value, Ok(json!(1)));
+    }
+
+    #[test]
+    fn set_stale_only_changes_once() {
+        let id = ValueId::new("stale", "once");
+
+        value::set_current(id, Ok(json!(true)));
+        value::set_stale(id, "gone".into());
+
+        let changed = value::current(id).last_changed;
+        value::set_stale(id, "gone".into());
+
+        assert_eq!(value::current(id).value, Err("gone".into()));
+        assert_eq!(value::current(id).last_changed, changed);
+    }
+
+    async fn idle(_: Task) -> anyhow::Result<()> {
+        futures::future::pending().await
+    }
+
+    async fn delete_cascade(task: Task) -> anyhow::Result<()> {
+        // Migrating a plain connection is not Send, which tasks have to be
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&pool).await?;
+
+        let mut conn = pool.acquire().await?;
+        let mut removed = super::removed();
+
+        for (id, parent) in [
+            ("bystander", None),
+            ("doomed", None),
+            ("doomed:child", Some("doomed")),
+        ] {
+            let device = Device {
+                id: id.into(),
+                name: id.into(),
+                device_type: DeviceType::Hardware,
+                parent: parent.map(Into::into),
+                task_spec: TaskSpec::NoOp,
+            };
+
+            device.save(&mut conn).await?;
+
+            let feature = Feature {
+                id: "level".into(),
+                name: "Level".into(),
+                virt: false,
+                direction: ValueDirection::SourceSink,
+                kind: ValueKind::Number,
+                meta: json!({}),
+                automate: None,
+            };
+
+            feature.save(id, &mut conn).await?;
+
+            let level = ValueId::new(id, "level");
+            value::set_current(level, Ok(json!(1)));
+            task.spawn(super::automation_label(level), idle);
+        }
+
+        let deleted = super::delete_device_tree("doomed", &mut conn).await?;
+        super::forget_devices(&task, deleted);
+
+        // Stopped right away, a failed assert would otherwise keep the group waiting on it
+        let bystander = super::automation_label(ValueId::new("bystander", "level"));
+        assert!(task.stop(&bystander));
+
+        for id in ["doomed", "doomed:child"] {
+            let level = ValueId::new(id, "level");
+            let label = super::automation_label(level);
+
+            assert!(!task.has_task(&label));
+            assert!(task.status().iter().all(|s| s.label != label));
+            assert_eq!(value::current(level).value, Ok(json!(null)));
+            assert!(Device::load_by_id(id, &mut conn).await.is_err());
+            assert_eq!(removed.next().await.unwrap().id, id);
+        }
+
+        // Devices that were not part of it stay
+        assert!(Device::load_by_id("bystander", &mut conn).await.is_ok());
+        assert!(task.status().iter().any(|s| s.label == bystander));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_device_cascades() {
+        let mut group = create_group(delete_cascade);
+        group.complete().await.unwrap();
+
+        assert_eq!(group.failed(), 0);
+    }
+}
@@ -113,7 +113,6 @@ fn get_set_j(h: f64, lw: f64, phi: f64, dec: f64, n: f64, m: f64, l: f64) -> f64
 /// let time_ms = sun::time_at_phase(unixtime, sun::SunPhase::Sunrise, lat, lon, 0.0);
 /// assert_eq!(time_ms, 1362463116241);
 /// ```
-
 pub fn time_at_phase(
     date: OffsetDateTime,
     sun_phase: SunPhase,
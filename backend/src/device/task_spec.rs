@@ -1,6 +1,9 @@
 use serde_derive::{Deserialize, Serialize};
 
-use crate::io::mqtt::{MqttServerInfo, MqttTopic};
+use crate::{
+    integration::mqtt::MqttDevice,
+    io::mqtt::{MqttServerInfo, MqttTopic},
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -9,8 +12,29 @@ pub enum TaskSpec {
     Zigbee2Mqtt(MqttServerInfo),
     #[serde(rename = "zigbee2MqttDevice")]
     Zigbee2MqttDevice(MqttTopic),
+    /// A zigbee2mqtt group, it is driven like a device on the topic of the group
+    #[serde(rename = "zigbee2MqttGroup")]
+    Zigbee2MqttGroup(MqttTopic),
+    #[serde(rename = "mqtt")]
+    Mqtt(MqttDevice),
+    #[serde(rename = "homeAssistant")]
+    HomeAssistant {
+        server: MqttServerInfo,
+        /// Topic prefix the discovery configs are published under
+        prefix: String,
+    },
+    #[serde(rename = "homeAssistantExport")]
+    HomeAssistantExport {
+        server: MqttServerInfo,
+        /// Topic prefix to publish discovery configs under
+        prefix: String,
+        /// Topic states and commands live under
+        topic: String,
+    },
     #[serde(rename = "noop")]
     NoOp,
     #[serde(rename = "sun")]
     Sun { lat: f64, lon: f64 },
+    #[serde(rename = "clock")]
+    Clock { utc_offset: i32 },
 }
@@ -0,0 +1,323 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::{StreamExt, TryStreamExt};
+use serde_json::Value as Json;
+use sqlx::{sqlite::SqliteRow, types::Json as SqlJson, Row, SqliteConnection};
+use time::OffsetDateTime;
+use tracing::{debug, error};
+
+use crate::{db, task::Task, value};
+
+/// How many days of history to keep for features that do not set `history_retention` in meta
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+/// How often we look for history that has passed its retention
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A recorded value, or a summary of the values in a bucket of time
+#[derive(Debug, async_graphql::SimpleObject)]
+pub struct Point {
+    /// When the value was recorded or the start of the bucket
+    pub time: OffsetDateTime,
+    /// The raw value, only set for raw points
+    pub value: Option<Json>,
+    /// The error the feature had, only set for raw points
+    pub error: Option<String>,
+    /// Smallest numeric value in the bucket
+    pub min: Option<f64>,
+    /// Largest numeric value in the bucket
+    pub max: Option<f64>,
+    /// Average of the numeric values in the bucket, booleans average to the fraction of time on
+    pub avg: Option<f64>,
+    /// How many values the point is made of
+    pub count: i64,
+}
+
+/// Record every value change into the history table and prune old history
+///
+/// Features can set `history_retention` in days in meta, `0` turns off recording for the feature.
+/// Database errors are logged, a missed point is better than no more history at all
+pub async fn history_task(task: Task) -> Result<()> {
+    let mut values = value::subscribe();
+    let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            next = values.next() => {
+                let Some((id, current)) = next else {
+                    break;
+                };
+
+                let time = current.last_changed.unwrap_or_else(OffsetDateTime::now_utc);
+                let _busy = task.busy();
+
+                let recorded = async {
+                    let mut conn = db::connection().await?;
+                    record(id.device.into(), id.feature.into(), time, current.value, &mut conn).await
+                };
+
+                if let Err(e) = recorded.await {
+                    error!("Could not record history for {id:?}\n{e:?}");
+                }
+            }
+            _ = prune.tick() => {
+                let _busy = task.busy();
+                let pruned = async {
+                    let mut conn = db::connection().await?;
+                    self::prune(OffsetDateTime::now_utc(), &mut conn).await
+                };
+
+                match pruned.await {
+                    Ok(removed) => debug!("pruned {removed} history points"),
+                    Err(e) => error!("Could not prune history\n{e:?}"),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn record(
+    device: &str,
+    feature: &str,
+    time: OffsetDateTime,
+    value: Result<Json, String>,
+    conn: &mut SqliteConnection,
+) -> Result<()> {
+    let (value, error) = match value {
+        Ok(v) => (Some(v), None),
+        Err(e) => (None, Some(e)),
+    };
+
+    sqlx::query(include_str!("../sql/history_insert.sql"))
+        .bind(device)
+        .bind(feature)
+        .bind(unix_ms(time))
+        .bind(value.map(SqlJson))
+        .bind(error)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Remove all history older than each features retention
+async fn prune(now: OffsetDateTime, conn: &mut SqliteConnection) -> Result<u64> {
+    let result = sqlx::query(include_str!("../sql/history_prune.sql"))
+        .bind(unix_ms(now))
+        .bind(DEFAULT_RETENTION_DAYS)
+        .execute(conn)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Load the history of a feature between `from` and `to`,
+/// if a bucket is given numeric values are summarised per bucket of time instead of returned raw
+pub async fn load(
+    device: &str,
+    feature: &str,
+    from: OffsetDateTime,
+    to: OffsetDateTime,
+    bucket: Option<Duration>,
+    conn: &mut SqliteConnection,
+) -> Result<Vec<Point>> {
+    let points = if let Some(bucket) = bucket {
+        let bucket = (bucket.as_millis() as i64).max(1);
+
+        sqlx::query(include_str!("../sql/history_bucket.sql"))
+            .bind(device)
+            .bind(feature)
+            .bind(unix_ms(from))
+            .bind(unix_ms(to))
+            .bind(bucket)
+            .try_map(|row: SqliteRow| {
+                Ok(Point {
+                    time: from_unix_ms(row.try_get("bucket")?),
+                    value: None,
+                    error: None,
+                    min: row.try_get("min")?,
+                    max: row.try_get("max")?,
+                    avg: row.try_get("avg")?,
+                    count: row.try_get("count")?,
+                })
+            })
+            .fetch(conn)
+            .try_collect()
+            .await?
+    } else {
+        sqlx::query(include_str!("../sql/history_range.sql"))
+            .bind(device)
+            .bind(feature)
+            .bind(unix_ms(from))
+            .bind(unix_ms(to))
+            .try_map(|row: SqliteRow| {
+                let value: Option<SqlJson<Json>> = row.try_get("value")?;
+
+                Ok(Point {
+                    time: from_unix_ms(row.try_get("time")?),
+                    value: value.map(|j| j.0),
+                    error: row.try_get("error")?,
+                    min: None,
+                    max: None,
+                    avg: None,
+                    count: 1,
+                })
+            })
+            .fetch(conn)
+            .try_collect()
+            .await?
+    };
+
+    Ok(points)
+}
+
+fn unix_ms(time: OffsetDateTime) -> i64 {
+    (time.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+fn from_unix_ms(ms: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(ms as i128 * 1_000_000)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use sqlx::{Connection, SqliteConnection};
+    use time::{macros::datetime, Duration};
+
+    use crate::device::{Device, DeviceType, Feature, TaskSpec, ValueDirection, ValueKind};
+
+    async fn database() -> SqliteConnection {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&mut conn).await.unwrap();
+
+        let device = Device {
+            id: "lamp".into(),
+            name: "Lamp".into(),
+            device_type: DeviceType::Hardware,
+            parent: None,
+            task_spec: TaskSpec::NoOp,
+        };
+
+        device.save(&mut conn).await.unwrap();
+
+        for (id, meta) in [
+            ("brightness", json!({})),
+            ("short", json!({ "history_retention": 1 })),
+            ("off", json!({ "history_retention": 0 })),
+        ] {
+            let feature = Feature {
+                id: id.into(),
+                name: id.into(),
+                virt: false,
+                direction: ValueDirection::Source,
+                kind: ValueKind::Number,
+                meta,
+                automate: None,
+            };
+
+            feature.save("lamp", &mut conn).await.unwrap();
+        }
+
+        conn
+    }
+
+    #[tokio::test]
+    async fn record_and_load() {
+        let mut conn = database().await;
+        let start = datetime!(2023-03-06 12:00 UTC);
+
+        for (minute, value) in [(0, json!(10)), (1, json!(20)), (5, json!(60))] {
+            let time = start + Duration::minutes(minute);
+            super::record("lamp", "brightness", time, Ok(value), &mut conn)
+                .await
+                .unwrap();
+        }
+
+        let time = start + Duration::minutes(6);
+        super::record("lamp", "brightness", time, Err("offline".into()), &mut conn)
+            .await
+            .unwrap();
+        super::record("lamp", "off", start, Ok(json!(1)), &mut conn)
+            .await
+            .unwrap();
+
+        let end = start + Duration::hours(1);
+
+        let raw = super::load("lamp", "brightness", start, end, None, &mut conn)
+            .await
+            .unwrap();
+
+        assert_eq!(raw.len(), 4);
+        assert_eq!(raw[0].time, start);
+        assert_eq!(raw[0].value, Some(json!(10)));
+        assert_eq!(raw[3].error.as_deref(), Some("offline"));
+
+        // Five minute buckets, errors are left out
+        let bucket = Some(std::time::Duration::from_secs(5 * 60));
+        let buckets = super::load("lamp", "brightness", start, end, bucket, &mut conn)
+            .await
+            .unwrap();
+
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].time, start);
+        assert_eq!(buckets[0].count, 2);
+        assert_eq!(buckets[0].min, Some(10.0));
+        assert_eq!(buckets[0].max, Some(20.0));
+        assert_eq!(buckets[0].avg, Some(15.0));
+        assert_eq!(buckets[1].count, 1);
+
+        // A retention of 0 turns recording off
+        let off = super::load("lamp", "off", start, end, None, &mut conn)
+            .await
+            .unwrap();
+        assert!(off.is_empty());
+    }
+
+    #[tokio::test]
+    async fn prune_by_retention() {
+        let mut conn = database().await;
+        let now = datetime!(2023-03-06 12:00 UTC);
+
+        for feature in ["brightness", "short"] {
+            for days in [0, 2, 40] {
+                let time = now - Duration::days(days);
+                super::record("lamp", feature, time, Ok(json!(days)), &mut conn)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // Default retention drops the 40 day old point, a retention of one day also the 2 day old
+        assert_eq!(super::prune(now, &mut conn).await.unwrap(), 3);
+
+        let from = now - Duration::days(100);
+        let kept = super::load(
+            "lamp",
+            "brightness",
+            from,
+            now + Duration::days(1),
+            None,
+            &mut conn,
+        )
+        .await
+        .unwrap();
+        assert_eq!(kept.len(), 2);
+
+        let kept = super::load(
+            "lamp",
+            "short",
+            from,
+            now + Duration::days(1),
+            None,
+            &mut conn,
+        )
+        .await
+        .unwrap();
+        assert_eq!(kept.len(), 1);
+    }
+}
@@ -36,7 +36,7 @@ async fn graphiql() -> impl IntoResponse {
 
 pub async fn listen(task: Task, address: SocketAddr) -> anyhow::Result<()> {
     let schema = Schema::build(Query, Mutation, Subscription)
-        .data(task)
+        .data(task.clone())
         .finish();
 
     let cors = CorsLayer::new()
@@ -59,8 +59,12 @@ pub async fn listen(task: Task, address: SocketAddr) -> anyhow::Result<()> {
     // `axum::Server` is a re-export of `hyper::Server`
     tracing::debug!("listening on {}", address);
 
+    // Requests that are being handled get to finish when the task is stopped
+    let _busy = task.busy();
+
     axum::Server::bind(&address)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(task.stopping())
         .await?;
 
     Ok(())
@@ -0,0 +1,464 @@
+use serde_derive::Deserialize;
+use serde_json::{json, Value as Json};
+
+use crate::device::{Feature, ValueDirection, ValueKind};
+
+/// The components we know how to turn into features
+const COMPONENTS: [&str; 7] = [
+    "sensor",
+    "binary_sensor",
+    "switch",
+    "light",
+    "number",
+    "select",
+    "text",
+];
+
+/// Where a discovery config was published, `<prefix>/<component>/[<node_id>/]<object_id>/config`
+#[derive(Debug, PartialEq, Eq)]
+pub struct DiscoveryTopic<'a> {
+    pub component: &'a str,
+    pub node_id: Option<&'a str>,
+    pub object_id: &'a str,
+}
+
+impl<'a> DiscoveryTopic<'a> {
+    pub fn parse(prefix: &str, topic: &'a str) -> Option<Self> {
+        let rest = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+        let rest = rest.strip_suffix("/config")?;
+
+        let parts: Vec<&str> = rest.split('/').collect();
+
+        let (component, node_id, object_id) = match parts[..] {
+            [component, object_id] => (component, None, object_id),
+            [component, node_id, object_id] => (component, Some(node_id), object_id),
+            _ => return None,
+        };
+
+        Some(DiscoveryTopic {
+            component,
+            node_id,
+            object_id,
+        })
+    }
+
+    pub fn supported(&self) -> bool {
+        COMPONENTS.contains(&self.component)
+    }
+
+    /// Feature ids only have to be unique on their device,
+    /// object ids are only unique per node so the node is part of it
+    pub fn feature_id(&self) -> String {
+        match self.node_id {
+            Some(node_id) => format!("{}_{}_{}", self.component, node_id, self.object_id),
+            None => format!("{}_{}", self.component, self.object_id),
+        }
+    }
+}
+
+/// The parts of a Home Assistant MQTT discovery config we use,
+/// devices often publish the abbreviated keys so those are accepted as well
+#[allow(unused)]
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(rename = "~")]
+    pub base: Option<String>,
+    pub name: Option<String>,
+    #[serde(alias = "uniq_id")]
+    pub unique_id: Option<String>,
+    #[serde(alias = "dev")]
+    pub device: Option<ConfigDevice>,
+    #[serde(alias = "stat_t")]
+    pub state_topic: Option<String>,
+    #[serde(alias = "cmd_t")]
+    pub command_topic: Option<String>,
+    #[serde(alias = "val_tpl")]
+    pub value_template: Option<String>,
+    #[serde(alias = "stat_val_tpl")]
+    pub state_value_template: Option<String>,
+    #[serde(alias = "cmd_tpl")]
+    pub command_template: Option<String>,
+    #[serde(alias = "pl_on")]
+    pub payload_on: Option<Json>,
+    #[serde(alias = "pl_off")]
+    pub payload_off: Option<Json>,
+    #[serde(alias = "unit_of_meas")]
+    pub unit_of_measurement: Option<String>,
+    #[serde(alias = "dev_cla")]
+    pub device_class: Option<String>,
+    #[serde(alias = "stat_cla")]
+    pub state_class: Option<String>,
+    #[serde(alias = "ops")]
+    pub options: Option<Vec<String>>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub step: Option<f64>,
+    pub schema: Option<String>,
+}
+
+#[allow(unused)]
+#[derive(Deserialize, Debug, Default)]
+pub struct ConfigDevice {
+    #[serde(alias = "ids")]
+    pub identifiers: Option<Json>,
+    pub name: Option<String>,
+    #[serde(alias = "mf")]
+    pub manufacturer: Option<String>,
+    #[serde(alias = "mdl")]
+    pub model: Option<String>,
+}
+
+impl ConfigDevice {
+    /// The first identifier, a device can have a list or a single one
+    pub fn identifier(&self) -> Option<String> {
+        let id = match self.identifiers.as_ref()? {
+            Json::Array(ids) => ids.first()?,
+            id => id,
+        };
+
+        match id {
+            Json::String(s) => Some(s.clone()),
+            Json::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    /// Topics can start or end with `~` to use the base topic
+    fn topic(&self, topic: &Option<String>) -> Option<String> {
+        let topic = topic.as_ref()?;
+
+        let Some(base) = &self.base else {
+            return Some(topic.clone());
+        };
+
+        if let Some(rest) = topic.strip_prefix('~') {
+            Some(format!("{base}{rest}"))
+        } else if let Some(rest) = topic.strip_suffix('~') {
+            Some(format!("{rest}{base}"))
+        } else {
+            Some(topic.clone())
+        }
+    }
+
+    /// Map a config onto a feature of a generic MQTT device
+    pub fn to_feature(&self, at: &DiscoveryTopic) -> anyhow::Result<Feature> {
+        let state_topic = self.topic(&self.state_topic);
+        let command_topic = self.topic(&self.command_topic);
+
+        let readonly = matches!(at.component, "sensor" | "binary_sensor");
+
+        let direction = match (state_topic.is_some(), command_topic.is_some() && !readonly) {
+            (true, true) => ValueDirection::SourceSink,
+            (true, false) => ValueDirection::Source,
+            (false, true) => ValueDirection::Sink,
+            (false, false) => anyhow::bail!("{} has neither state nor command topic", at.object_id),
+        };
+
+        let json_schema = self.schema.as_deref() == Some("json");
+
+        let mut meta = serde_json::Map::new();
+
+        let kind = match at.component {
+            "sensor" => {
+                if let Some(options) = &self.options {
+                    meta.insert("possible".into(), json!(options));
+                    ValueKind::State
+                } else if self.unit_of_measurement.is_some() || self.state_class.is_some() {
+                    ValueKind::Number
+                } else {
+                    ValueKind::String
+                }
+            }
+            "binary_sensor" | "switch" | "light" => {
+                let on = self.payload_on.clone().unwrap_or(json!("ON"));
+                let off = self.payload_off.clone().unwrap_or(json!("OFF"));
+
+                meta.insert("value_on".into(), on);
+                meta.insert("value_off".into(), off);
+
+                ValueKind::Bool
+            }
+            "number" => {
+                if let Some(min) = self.min {
+                    meta.insert("min".into(), json!(min));
+                }
+
+                if let Some(max) = self.max {
+                    meta.insert("max".into(), json!(max));
+                }
+
+                if let Some(step) = self.step {
+                    meta.insert("step".into(), json!(step));
+                }
+
+                ValueKind::Number
+            }
+            "select" => {
+                meta.insert(
+                    "possible".into(),
+                    json!(self.options.clone().unwrap_or_default()),
+                );
+                ValueKind::State
+            }
+            "text" => ValueKind::String,
+            other => anyhow::bail!("Home Assistant component {other} is not supported"),
+        };
+
+        if let Some(unit) = &self.unit_of_measurement {
+            meta.insert("unit".into(), json!(unit));
+        }
+
+        if let Some(topic) = state_topic {
+            meta.insert("state_topic".into(), json!(topic));
+        }
+
+        if let Some(topic) = command_topic {
+            meta.insert("command_topic".into(), json!(topic));
+        }
+
+        if json_schema {
+            // JSON lights keep their on/off state under `state` both ways
+            meta.insert("pointer".into(), json!("/state"));
+        } else {
+            let template = self
+                .value_template
+                .as_ref()
+                .or(self.state_value_template.as_ref());
+
+            if let Some(template) = template {
+                let Some(pointer) = template_pointer(template) else {
+                    anyhow::bail!("Unsupported value template {template:?}");
+                };
+
+                meta.insert("pointer".into(), json!(pointer));
+            }
+
+            // The pointer is only for reading, commands are sent as a plain value
+            // unless there is a template saying otherwise
+            let command = match &self.command_template {
+                Some(template) => command_template(template)
+                    .ok_or_else(|| anyhow::anyhow!("Unsupported command template {template:?}"))?,
+                None => "{{value}}".into(),
+            };
+
+            meta.insert("payload_template".into(), json!(command));
+        }
+
+        let name = self
+            .name
+            .clone()
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| at.object_id.replace('_', " "));
+
+        Ok(Feature {
+            id: at.feature_id(),
+            name,
+            virt: false,
+            direction,
+            kind,
+            meta: meta.into(),
+            automate: None,
+        })
+    }
+}
+
+/// Turn the simple value templates like `{{ value_json.ENERGY.Power | float }}`
+/// or `{{ value_json['temp'] }}` into a JSON pointer, more involved templates are not supported
+fn template_pointer(template: &str) -> Option<String> {
+    let inner = template.trim().strip_prefix("{{")?.strip_suffix("}}")?;
+
+    // Filters like `| float` or `| round(1)` do not change where the value is
+    let expression = inner.split('|').next()?.trim();
+
+    if expression == "value" {
+        return Some(String::new());
+    }
+
+    let mut rest = expression.strip_prefix("value_json")?;
+    let mut pointer = String::new();
+
+    while !rest.is_empty() {
+        let key = if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            rest = &r[end..];
+
+            let key = &r[..end];
+
+            if !key
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+            {
+                return None;
+            }
+
+            key
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']')?;
+            rest = &r[end + 1..];
+            r[..end].trim().trim_matches(['\'', '"'])
+        } else {
+            return None;
+        };
+
+        if key.is_empty() {
+            return None;
+        }
+
+        pointer.push('/');
+        pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+    }
+
+    Some(pointer)
+}
+
+/// Command templates are only supported when all they do is place the value
+fn command_template(template: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}")? + start;
+
+        if rest[start + 2..end].trim() != "value" {
+            return None;
+        }
+
+        out.push_str(&rest[..start]);
+        out.push_str("{{value}}");
+        rest = &rest[end + 2..];
+    }
+
+    if rest.contains("{%") {
+        return None;
+    }
+
+    out.push_str(rest);
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn templates() {
+        assert_eq!(template_pointer("{{ value }}").unwrap(), "");
+        assert_eq!(
+            template_pointer("{{ value_json.ENERGY.Power | float }}").unwrap(),
+            "/ENERGY/Power"
+        );
+        assert_eq!(
+            template_pointer("{{value_json['temp/c']}}").unwrap(),
+            "/temp~1c"
+        );
+        assert!(template_pointer("{{ value_json.a if value_json.b }}").is_none());
+
+        assert_eq!(
+            command_template("{\"on\": {{ value }}}").unwrap(),
+            "{\"on\": {{value}}}"
+        );
+        assert!(command_template("{{ value * 2 }}").is_none());
+    }
+
+    #[test]
+    fn tasmota_switch() {
+        let topic = "homeassistant/switch/A1B2C3_RL_1/config";
+        let at = DiscoveryTopic::parse("homeassistant", topic).unwrap();
+
+        assert_eq!(at.component, "switch");
+        assert_eq!(at.object_id, "A1B2C3_RL_1");
+
+        let config: Config = serde_json::from_value(json!({
+            "name": "Plug",
+            "~": "plug/",
+            "stat_t": "~tele/STATE",
+            "val_tpl": "{{value_json.POWER}}",
+            "cmd_t": "~cmnd/POWER",
+            "pl_on": "ON",
+            "pl_off": "OFF",
+            "uniq_id": "A1B2C3_RL_1",
+            "dev": { "ids": ["A1B2C3"], "name": "Plug" },
+        }))
+        .unwrap();
+
+        let feature = config.to_feature(&at).unwrap();
+
+        assert_eq!(feature.id, "switch_A1B2C3_RL_1");
+        assert_eq!(feature.kind, ValueKind::Bool);
+        assert_eq!(feature.direction, ValueDirection::SourceSink);
+        assert_eq!(feature.meta["state_topic"], json!("plug/tele/STATE"));
+        assert_eq!(feature.meta["command_topic"], json!("plug/cmnd/POWER"));
+        assert_eq!(feature.meta["pointer"], json!("/POWER"));
+        assert_eq!(feature.meta["payload_template"], json!("{{value}}"));
+        assert_eq!(config.device.unwrap().identifier().unwrap(), "A1B2C3");
+    }
+
+    #[test]
+    fn sensors() {
+        let at =
+            DiscoveryTopic::parse("homeassistant", "homeassistant/sensor/esp/temp/config").unwrap();
+
+        assert_eq!(at.node_id, Some("esp"));
+        assert_eq!(at.object_id, "temp");
+
+        let config: Config = serde_json::from_value(json!({
+            "state_topic": "esp/sensor/temp/state",
+            "unit_of_measurement": "°C",
+        }))
+        .unwrap();
+
+        let feature = config.to_feature(&at).unwrap();
+
+        assert_eq!(feature.id, "sensor_esp_temp");
+        assert_eq!(feature.kind, ValueKind::Number);
+        assert_eq!(feature.direction, ValueDirection::Source);
+        assert_eq!(feature.name, "temp");
+
+        // The same object id on another node is another entity
+        let other = DiscoveryTopic::parse("homeassistant", "homeassistant/sensor/esp2/temp/config")
+            .unwrap();
+        assert_eq!(other.feature_id(), "sensor_esp2_temp");
+
+        // Without a unit it could be anything, numbers still come in as their text
+        let config: Config = serde_json::from_value(json!({
+            "state_topic": "esp/sensor/uptime/state",
+        }))
+        .unwrap();
+
+        let feature = config.to_feature(&at).unwrap();
+
+        assert_eq!(feature.kind, ValueKind::String);
+        assert_eq!(
+            crate::integration::mqtt::read(&feature, &json!(3600)),
+            Ok(json!("3600"))
+        );
+
+        let light =
+            DiscoveryTopic::parse("homeassistant", "homeassistant/light/lamp/config").unwrap();
+
+        let config: Config = serde_json::from_value(json!({
+            "schema": "json",
+            "state_topic": "lamp/state",
+            "command_topic": "lamp/set",
+        }))
+        .unwrap();
+
+        let feature = config.to_feature(&light).unwrap();
+
+        assert_eq!(feature.direction, ValueDirection::SourceSink);
+        assert_eq!(feature.meta["pointer"], json!("/state"));
+        assert!(feature.meta.get("payload_template").is_none());
+
+        assert!(DiscoveryTopic::parse("homeassistant", "homeassistant/sensor/config").is_none());
+        assert!(
+            !DiscoveryTopic::parse("homeassistant", "homeassistant/camera/x/config")
+                .unwrap()
+                .supported()
+        );
+    }
+}
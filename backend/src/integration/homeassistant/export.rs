@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use futures::{StreamExt, TryStreamExt};
+use serde_json::{json, Value as Json};
+use tracing::{debug, warn};
+
+use crate::{
+    db,
+    device::{Device, DeviceType, Feature, ValueDirection, ValueKind},
+    io::mqtt::{self, MqttServerInfo, MqttTopic},
+    strings::IString,
+    value::{self, ValueId},
+};
+
+/// Unique ids of everything we publish start with this, so our own
+/// discovery consumer can tell them apart from real devices
+pub const UNIQUE_ID_PREFIX: &str = "bramble_";
+
+/// Publishes virtual devices, value buffers and automation targets as Home Assistant
+/// discovery configs, states go to `<topic>/<device>/<feature>/state` and commands are read from
+/// `<topic>/<device>/<feature>/set`. Configs and states are retained, `<topic>/status` is
+/// `offline` while we are gone
+pub struct Exporter {
+    server: MqttServerInfo,
+    prefix: String,
+    topic: String,
+    /// Command topic to the feature it writes to
+    commands: HashMap<String, (ValueId, Feature)>,
+    /// State topics of exported features
+    states: HashMap<ValueId, String>,
+    /// Discovery config topics we published for each device
+    configs: HashMap<String, Vec<String>>,
+}
+
+impl Exporter {
+    pub fn new(server: MqttServerInfo, prefix: String, topic: String) -> Self {
+        Exporter {
+            server,
+            prefix,
+            topic,
+            commands: HashMap::new(),
+            states: HashMap::new(),
+            configs: HashMap::new(),
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let mut incoming = mqtt::incoming_lagged();
+        let mut changed = crate::device::changed();
+        let mut removed = crate::device::removed();
+        let mut values = value::subscribe_lagged();
+
+        let birth = format!("{}/status", self.prefix);
+        let commands = format!("{}/+/+/set", self.topic);
+
+        // Before subscribing so the first connection already has it
+        mqtt::last_will(self.topic(&self.status_topic()), b"offline".to_vec());
+
+        for topic in [&birth, &commands] {
+            mqtt::subscribe(self.topic(topic));
+        }
+
+        self.export_all().await?;
+
+        loop {
+            tokio::select! {
+                Some(next) = incoming.next() => {
+                    let Ok((topic, data)) = next else {
+                        // Commands are gone, starting over at least exports everything again
+                        anyhow::bail!("Missed commands from Home Assistant, starting over");
+                    };
+
+                    let payload = String::from_utf8_lossy(&data);
+
+                    if topic == birth {
+                        // Home Assistant restarted and forgot about us
+                        if payload == "online" {
+                            self.export_all().await?;
+                        }
+                    } else {
+                        self.command(&topic, &payload);
+                    }
+                }
+                Some(device) = changed.next() => {
+                    self.export_device(&device).await?;
+                }
+                Some(device) = removed.next() => {
+                    self.forget(&device.id);
+                    self.unexport(&device.id, &[]);
+                }
+                Some(next) = values.next() => match next {
+                    Ok((id, current)) => {
+                        if let Ok(value) = current.value {
+                            self.publish_state(id, &value);
+                        }
+                    }
+                    // Catch up on the states we missed
+                    Err(_) => self.publish_states(),
+                },
+                else => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn export_all(&mut self) -> Result<()> {
+        let devices: Vec<Device> = {
+            let mut conn = db::connection().await?;
+            Device::all(&mut conn).try_collect().await?
+        };
+
+        self.publish(&self.status_topic(), "online".into());
+
+        for device in devices {
+            self.export_device(&device).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn export_device(&mut self, device: &Device) -> Result<()> {
+        if !exportable(device) {
+            return Ok(());
+        }
+
+        let features: Vec<Feature> = {
+            let mut conn = db::connection().await?;
+            Feature::load_by_device(&device.id, &mut conn)
+                .try_collect()
+                .await?
+        };
+
+        self.forget(&device.id);
+
+        let mut configs = vec![];
+
+        for feature in features.into_iter().filter(|f| exported(device, f)) {
+            let id = ValueId::new(&device.id, &feature.id);
+            let base = format!("{}/{}/{}", self.topic, device.id, feature.id);
+            let state_topic = format!("{base}/state");
+            let command_topic = format!("{base}/set");
+
+            let (component, config) = discovery_config(device, &feature, &self.topic, &base);
+            let object_id = object_id(device, &feature);
+
+            debug!("Export {id:?} as {component} {object_id}");
+
+            let config_topic = format!("{}/{component}/bramble/{object_id}/config", self.prefix);
+            self.publish(&config_topic, config.to_string());
+            configs.push(config_topic);
+
+            if feature.direction.can_read() {
+                self.states.insert(id, state_topic);
+
+                if let Ok(value) = &value::current(id).value {
+                    self.publish_state(id, value);
+                }
+            }
+
+            if feature.direction != ValueDirection::Source {
+                self.commands.insert(command_topic, (id, feature));
+            }
+        }
+
+        // Features that are gone, or changed component, have to be removed from Home Assistant
+        self.unexport(&device.id, &configs);
+        self.configs.insert(device.id.clone(), configs);
+
+        Ok(())
+    }
+
+    /// Remove the configs of a device except the ones to keep,
+    /// an empty config removes the entity in Home Assistant
+    fn unexport(&mut self, device_id: &str, keep: &[String]) {
+        let Some(published) = self.configs.remove(device_id) else {
+            return;
+        };
+
+        for topic in published.iter().filter(|t| !keep.contains(t)) {
+            self.publish(topic, String::new());
+        }
+    }
+
+    /// Stop publishing states and accepting commands for a device
+    fn forget(&mut self, device_id: &str) {
+        let device = IString::from(device_id);
+
+        self.states.retain(|id, _| id.device != device);
+        self.commands.retain(|_, (id, _)| id.device != device);
+    }
+
+    fn publish_state(&self, id: ValueId, value: &Json) {
+        if value.is_null() {
+            return;
+        }
+
+        if let Some(topic) = self.states.get(&id) {
+            self.publish(topic, raw(value));
+        }
+    }
+
+    fn publish_states(&self) {
+        for id in self.states.keys() {
+            if let Ok(value) = &value::current(*id).value {
+                self.publish_state(*id, value);
+            }
+        }
+    }
+
+    fn command(&self, topic: &str, payload: &str) {
+        let Some((id, feature)) = self.commands.get(topic) else {
+            return;
+        };
+
+        match parse_command(feature, payload) {
+            Ok(value) => value::push(*id, value),
+            Err(e) => warn!("Invalid command for {id:?} on {topic}, {e}"),
+        }
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.topic)
+    }
+
+    fn topic(&self, topic: &str) -> MqttTopic {
+        MqttTopic {
+            server: self.server.clone(),
+            topic: topic.into(),
+        }
+    }
+
+    /// Everything we publish is retained so Home Assistant finds it after a restart of its own
+    fn publish(&self, topic: &str, payload: String) {
+        mqtt::publish_retained(self.topic(topic), payload.into_bytes());
+    }
+}
+
+/// Integrations and devices that came from Home Assistant are not exported
+fn exportable(device: &Device) -> bool {
+    !matches!(device.device_type, DeviceType::Integration { .. }) && !device.id.starts_with("hass:")
+}
+
+/// Only what lives in bramble itself, virtual devices, value buffers and features
+/// with an automation. Hardware is already known to Home Assistant through its own integration
+fn exported(device: &Device, feature: &Feature) -> bool {
+    matches!(device.device_type, DeviceType::Virtual { .. })
+        || feature.virt
+        || feature.automate.is_some()
+}
+
+/// Discovery object ids can only contain letters, digits, `_` and `-`
+fn object_id(device: &Device, feature: &Feature) -> String {
+    format!("{}_{}", device.id, feature.id)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn discovery_config(
+    device: &Device,
+    feature: &Feature,
+    topic: &str,
+    base: &str,
+) -> (&'static str, Json) {
+    let writable = feature.direction != ValueDirection::Source;
+    // Pushing `true` presses a momentary feature, it has no state worth showing
+    let button = writable && feature.meta.get("momentary") == Some(&Json::Bool(true));
+    let object_id = object_id(device, feature);
+
+    let mut config = json!({
+        "name": feature.name,
+        "unique_id": format!("{UNIQUE_ID_PREFIX}{object_id}"),
+        "object_id": object_id,
+        "availability_topic": format!("{topic}/status"),
+        "device": {
+            "identifiers": [format!("{UNIQUE_ID_PREFIX}{}", device.id)],
+            "name": device.name,
+            "manufacturer": "bramble",
+        },
+    });
+
+    if feature.direction.can_read() && !button {
+        config["state_topic"] = json!(format!("{base}/state"));
+    }
+
+    if writable {
+        config["command_topic"] = json!(format!("{base}/set"));
+    }
+
+    if let Some(unit) = feature.meta.get("unit") {
+        config["unit_of_measurement"] = unit.clone();
+    }
+
+    let possible = feature.meta.get("possible").cloned().unwrap_or(json!([]));
+
+    let component = match (feature.kind, writable) {
+        _ if button => {
+            config["payload_press"] = json!("true");
+            "button"
+        }
+        (ValueKind::Bool, writable) => {
+            config["payload_on"] = json!("true");
+            config["payload_off"] = json!("false");
+
+            if writable {
+                "switch"
+            } else {
+                "binary_sensor"
+            }
+        }
+        (ValueKind::Number, true) => {
+            config["min"] = feature
+                .meta
+                .get("min")
+                .cloned()
+                .unwrap_or(json!(-1_000_000));
+            config["max"] = feature.meta.get("max").cloned().unwrap_or(json!(1_000_000));
+
+            if let Some(step) = feature.meta.get("step") {
+                config["step"] = step.clone();
+            }
+
+            "number"
+        }
+        (ValueKind::Number, false) => {
+            config["state_class"] = json!("measurement");
+            "sensor"
+        }
+        (ValueKind::State, true) => {
+            config["options"] = possible;
+            "select"
+        }
+        (ValueKind::State, false) => {
+            config["device_class"] = json!("enum");
+            config["options"] = possible;
+            "sensor"
+        }
+        (ValueKind::String, true) => "text",
+        (ValueKind::String, false) => "sensor",
+        // Home Assistant has no entity for structured values, they are sent as JSON text
+        (ValueKind::Object | ValueKind::Array, true) => "text",
+        (ValueKind::Object | ValueKind::Array, false) => "sensor",
+    };
+
+    (component, config)
+}
+
+/// States are published without JSON quoting so strings can be used as is
+fn raw(value: &Json) -> String {
+    match value {
+        Json::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn parse_command(feature: &Feature, payload: &str) -> Result<Json, String> {
+    let payload = payload.trim();
+
+    let value = match feature.kind {
+        ValueKind::Bool => match payload.to_lowercase().as_str() {
+            "true" | "on" | "1" => Json::Bool(true),
+            "false" | "off" | "0" => Json::Bool(false),
+            other => return Err(format!("{other} is not a boolean")),
+        },
+        ValueKind::Number => match serde_json::from_str::<Json>(payload) {
+            Ok(n @ Json::Number(_)) => n,
+            _ => return Err(format!("{payload} is not a number")),
+        },
+        ValueKind::State | ValueKind::String => Json::String(payload.into()),
+        ValueKind::Object | ValueKind::Array => match serde_json::from_str::<Json>(payload) {
+            Ok(json) => json,
+            Err(e) => return Err(format!("{payload} is not valid JSON, {e}")),
+        },
+    };
+
+    feature.validate(&value)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::device::{TaskSpec, VirtualType};
+
+    fn feature(kind: ValueKind, direction: ValueDirection, meta: Json) -> Feature {
+        Feature {
+            id: "virtual:1f".into(),
+            name: "Level".into(),
+            virt: true,
+            direction,
+            kind,
+            meta,
+            automate: None,
+        }
+    }
+
+    #[test]
+    fn configs() {
+        let device = Device {
+            id: "virtual:a0".into(),
+            name: "Living room".into(),
+            device_type: DeviceType::Virtual {
+                vty: VirtualType::Slider,
+            },
+            parent: None,
+            task_spec: TaskSpec::NoOp,
+        };
+
+        let slider = feature(
+            ValueKind::Number,
+            ValueDirection::SourceSink,
+            json!({"min": 0, "max": 10}),
+        );
+
+        let (component, config) = discovery_config(&device, &slider, "bramble", "bramble/x");
+
+        assert_eq!(component, "number");
+        assert_eq!(config["unique_id"], json!("bramble_virtual_a0_virtual_1f"));
+        assert_eq!(config["command_topic"], json!("bramble/x/set"));
+        assert_eq!(config["state_topic"], json!("bramble/x/state"));
+        assert_eq!(config["max"], json!(10));
+
+        let sensor = feature(
+            ValueKind::State,
+            ValueDirection::Source,
+            json!({"possible": ["a"]}),
+        );
+        let (component, config) = discovery_config(&device, &sensor, "bramble", "bramble/x");
+
+        assert_eq!(component, "sensor");
+        assert_eq!(config["options"], json!(["a"]));
+        assert!(config.get("command_topic").is_none());
+
+        let press = feature(
+            ValueKind::Bool,
+            ValueDirection::SourceSink,
+            json!({"momentary": true}),
+        );
+        let (component, config) = discovery_config(&device, &press, "bramble", "bramble/x");
+
+        assert_eq!(component, "button");
+        assert_eq!(config["payload_press"], json!("true"));
+        assert_eq!(config["command_topic"], json!("bramble/x/set"));
+        assert!(config.get("state_topic").is_none());
+    }
+
+    #[test]
+    fn commands() {
+        let toggle = feature(ValueKind::Bool, ValueDirection::SourceSink, json!({}));
+
+        assert_eq!(parse_command(&toggle, "ON"), Ok(json!(true)));
+        assert_eq!(parse_command(&toggle, "false"), Ok(json!(false)));
+        assert!(parse_command(&toggle, "maybe").is_err());
+
+        let slider = feature(ValueKind::Number, ValueDirection::SourceSink, json!({}));
+
+        assert_eq!(parse_command(&slider, "42"), Ok(json!(42)));
+        assert!(parse_command(&slider, "high").is_err());
+
+        let state = feature(
+            ValueKind::State,
+            ValueDirection::SourceSink,
+            json!({"possible": ["a"]}),
+        );
+
+        assert_eq!(parse_command(&state, "a"), Ok(json!("a")));
+        assert!(parse_command(&state, "b").is_err());
+    }
+
+    #[test]
+    fn only_bramble_features_are_exported() {
+        let lamp = Device {
+            id: "zigbee:lamp".into(),
+            name: "Lamp".into(),
+            device_type: DeviceType::Hardware,
+            parent: None,
+            task_spec: TaskSpec::NoOp,
+        };
+
+        let mut state = feature(ValueKind::Bool, ValueDirection::SourceSink, json!({}));
+        state.virt = false;
+
+        assert!(!exported(&lamp, &state));
+
+        let buffer = feature(ValueKind::Number, ValueDirection::SourceSink, json!({}));
+        assert!(exported(&lamp, &buffer));
+
+        state.automate = Some(
+            serde_json::from_value(json!({
+                "nodes": [],
+                "connections": [],
+                "counter": 0,
+                "defaults": [],
+            }))
+            .unwrap(),
+        );
+        assert!(exported(&lamp, &state));
+    }
+}
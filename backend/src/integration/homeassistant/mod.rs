@@ -0,0 +1,265 @@
+mod discovery;
+mod export;
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use bytes::Buf;
+use futures::{StreamExt, TryStreamExt};
+use serde_json::json;
+use sqlx::SqliteConnection;
+use tracing::{debug, warn};
+
+use super::{connection_features, connection_status, mqtt::MqttDevice};
+use crate::{
+    db,
+    device::{spawn_device_tasks, Device, DeviceType, Feature, TaskSpec},
+    io::mqtt::{self, MqttServerInfo, MqttTopic},
+    task::Task,
+    value::ValueId,
+};
+use discovery::{Config, DiscoveryTopic};
+use export::{Exporter, UNIQUE_ID_PREFIX};
+
+pub const DEFAULT_PREFIX: &str = "homeassistant";
+/// Feature meta with the topic a feature was discovered on
+const DISCOVERY_TOPIC: &str = "discovery_topic";
+/// Where exported states are published and commands are read from
+pub const DEFAULT_EXPORT_TOPIC: &str = "bramble";
+
+pub async fn create_integration_device(
+    server: MqttServerInfo,
+    prefix: String,
+    task: &Task,
+    conn: &mut SqliteConnection,
+) -> Result<Arc<Device>> {
+    let device = Device {
+        id: format!("hass:{}:{}", server.host, server.port),
+        name: format!("Home Assistant discovery ({}:{})", server.host, server.port),
+        device_type: DeviceType::Integration {
+            name: "homeassistant".into(),
+        },
+        parent: None,
+        task_spec: TaskSpec::HomeAssistant { server, prefix },
+    };
+
+    device.save(conn).await?;
+
+    for feature in connection_features() {
+        feature.save(&device.id, conn).await?;
+    }
+
+    spawn_device_tasks(task, &device);
+
+    let device = Arc::new(device);
+
+    crate::device::notify_changed(device.clone());
+
+    Ok(device)
+}
+
+pub async fn create_export_device(
+    server: MqttServerInfo,
+    prefix: String,
+    topic: String,
+    task: &Task,
+    conn: &mut SqliteConnection,
+) -> Result<Arc<Device>> {
+    let device = Device {
+        id: format!("hass-export:{}:{}", server.host, server.port),
+        name: format!("Home Assistant export ({}:{})", server.host, server.port),
+        device_type: DeviceType::Integration {
+            name: "homeassistant_export".into(),
+        },
+        parent: None,
+        task_spec: TaskSpec::HomeAssistantExport {
+            server,
+            prefix,
+            topic,
+        },
+    };
+
+    device.save(conn).await?;
+
+    for feature in connection_features() {
+        feature.save(&device.id, conn).await?;
+    }
+
+    spawn_device_tasks(task, &device);
+
+    let device = Arc::new(device);
+
+    crate::device::notify_changed(device.clone());
+
+    Ok(device)
+}
+
+pub async fn export_update(
+    (parent, server, prefix, topic): (String, MqttServerInfo, String, String),
+    _: Task,
+) -> Result<()> {
+    let exporter = Exporter::new(server.clone(), prefix, topic);
+
+    // The first part to finish or fail ends the integration task so it can be restarted
+    tokio::select! {
+        res = exporter.run() => res,
+        res = connection_status(&parent, &server) => res,
+    }
+}
+
+pub async fn discovery_update(
+    (parent, server, prefix): (String, MqttServerInfo, String),
+    task: Task,
+) -> Result<()> {
+    // The first part to finish or fail ends the integration task so it can be restarted
+    tokio::select! {
+        res = discovered_devices(&parent, &server, &prefix, &task) => res,
+        res = connection_status(&parent, &server) => res,
+    }
+}
+
+async fn discovered_devices(
+    parent: &str,
+    server: &MqttServerInfo,
+    prefix: &str,
+    task: &Task,
+) -> Result<()> {
+    let mut channel = mqtt::incoming_lagged();
+
+    // Discovery topics with and without a node id
+    for wildcard in ["+/+/config", "+/+/+/config"] {
+        mqtt::subscribe(MqttTopic {
+            topic: format!("{prefix}/{wildcard}"),
+            server: server.clone(),
+        });
+    }
+
+    // Which feature a discovery topic created, so an empty config can remove it again
+    let mut discovered = load_discovered(parent).await?;
+
+    while let Some(next) = channel.next().await {
+        let Ok((topic, data)) = next else {
+            // Starting over subscribes again, which gets us the retained configs once more
+            anyhow::bail!("Missed discovery configs, starting over");
+        };
+
+        let Some(at) = DiscoveryTopic::parse(prefix, &topic) else {
+            continue;
+        };
+
+        if !at.supported() {
+            continue;
+        }
+
+        if data.is_empty() {
+            if let Some(id) = discovered.remove(&topic) {
+                debug!("{topic} removed, deleting {id:?}");
+                crate::device::delete_feature(task, id.device.into(), id.feature.into()).await?;
+            }
+
+            continue;
+        }
+
+        let config: Config = match serde_json::de::from_reader(data.reader()) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Invalid discovery config on {topic}\n{e:?}");
+                continue;
+            }
+        };
+
+        let exported = config
+            .unique_id
+            .as_ref()
+            .map(|id| id.starts_with(UNIQUE_ID_PREFIX))
+            .unwrap_or(false);
+
+        if exported {
+            // Do not import what we exported ourselves
+            continue;
+        }
+
+        let mut feature = match config.to_feature(&at) {
+            Ok(feature) => feature,
+            Err(e) => {
+                warn!("Can not use discovery config on {topic}\n{e:?}");
+                continue;
+            }
+        };
+
+        if let Some(meta) = feature.meta.as_object_mut() {
+            meta.insert(DISCOVERY_TOPIC.into(), json!(topic));
+        }
+
+        // Entities of the same device end up as features on one device
+        let (device_id, device_name) = match &config.device {
+            Some(dev) => match dev.identifier() {
+                Some(ident) => (
+                    format!("hass:{ident}"),
+                    dev.name.clone().unwrap_or_else(|| feature.name.clone()),
+                ),
+                None => (format!("hass:{}", at.feature_id()), feature.name.clone()),
+            },
+            None => (format!("hass:{}", at.feature_id()), feature.name.clone()),
+        };
+
+        let state_topic = feature
+            .meta
+            .get("state_topic")
+            .and_then(|t| t.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        let device = Device {
+            id: device_id,
+            name: device_name,
+            device_type: DeviceType::Hardware,
+            parent: Some(parent.into()),
+            task_spec: TaskSpec::Mqtt(MqttDevice {
+                server: server.clone(),
+                state_topic,
+                command_topic: None,
+                payload_template: None,
+            }),
+        };
+
+        {
+            let _busy = task.busy();
+            let mut tx = db::begin().await?;
+
+            device.save(&mut tx).await?;
+            feature.save(&device.id, &mut tx).await?;
+
+            tx.commit().await?;
+        }
+
+        discovered.insert(topic, ValueId::new(&device.id, &feature.id));
+
+        // Restart the device so it picks up the feature
+        spawn_device_tasks(task, &device);
+
+        crate::device::notify_changed(device);
+    }
+
+    Ok(())
+}
+
+/// Which feature each discovery topic created, the topic is kept in the feature meta
+async fn load_discovered(parent: &str) -> Result<HashMap<String, ValueId>> {
+    let mut conn = db::connection().await?;
+
+    let devices: Vec<Device> = Device::children(parent, &mut conn).try_collect().await?;
+    let mut discovered = HashMap::new();
+
+    for device in devices {
+        let mut features = Feature::load_by_device(&device.id, &mut conn);
+
+        while let Some(feature) = features.try_next().await? {
+            if let Some(topic) = feature.meta.get(DISCOVERY_TOPIC).and_then(|t| t.as_str()) {
+                discovered.insert(topic.to_string(), ValueId::new(&device.id, &feature.id));
+            }
+        }
+    }
+
+    Ok(discovered)
+}
@@ -1,2 +1,64 @@
+pub mod homeassistant;
+pub mod mqtt;
 pub mod zigbee2mqtt;
 
+use anyhow::Result;
+use futures::StreamExt;
+use serde_json::json;
+
+use crate::{
+    device::{ValueDirection, ValueKind},
+    io::mqtt::{self as mqtt_io, MqttServerInfo},
+    value::{self, ValueId},
+};
+
+/// Features showing the MQTT connection state of an integration device
+pub fn connection_features() -> Vec<crate::device::Feature> {
+    vec![
+        crate::device::Feature {
+            id: "connection".into(),
+            name: "Connection".into(),
+            virt: false,
+            direction: ValueDirection::Source,
+            kind: ValueKind::State,
+            meta: json!({
+                "possible": ["connecting", "connected", "backing_off"],
+            }),
+            automate: None,
+        },
+        crate::device::Feature {
+            id: "connection_error".into(),
+            name: "Connection error".into(),
+            virt: false,
+            direction: ValueDirection::Source,
+            kind: ValueKind::String,
+            meta: json!({}),
+            automate: None,
+        },
+    ]
+}
+
+/// Mirror the state of the MQTT connection onto the integration device
+pub async fn connection_status(parent: &str, server: &MqttServerInfo) -> Result<()> {
+    let state_id = ValueId::new(parent, "connection");
+    let error_id = ValueId::new(parent, "connection_error");
+
+    let set = |status: mqtt_io::ConnectionStatus| {
+        value::set_current(state_id, Ok(json!(status.state.as_str())));
+        value::set_current(error_id, Ok(json!(status.last_error)));
+    };
+
+    let mut changes = mqtt_io::connection_changes();
+
+    if let Some(status) = mqtt_io::connection_status(server) {
+        set(status);
+    }
+
+    while let Some((changed, status)) = changes.next().await {
+        if &changed == server {
+            set(status);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,324 @@
+use std::{collections::BTreeSet, future, sync::Arc};
+
+use anyhow::Result;
+use bytes::{Buf, Bytes};
+use futures::{StreamExt, TryStreamExt};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use tracing::warn;
+
+use crate::{
+    db,
+    device::{
+        forget_features, spawn_device_tasks, Device, DeviceType, Feature, TaskSpec, ValueKind,
+    },
+    io::mqtt::{self, MqttServerInfo, MqttTopic},
+    strings::IString,
+    task::Task,
+    value::{self, ValueId},
+};
+
+/// A device that speaks plain MQTT, features are mapped into its payloads with JSON pointers
+///
+/// Features can override the topics and template of the device in their meta with
+/// `state_topic`, `command_topic` and `payload_template`, `pointer` picks the value out
+/// of a JSON payload, without it the whole payload is the value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttDevice {
+    pub server: MqttServerInfo,
+    /// Topic the device publishes its state to
+    pub state_topic: String,
+    /// Topic to publish values written to the device to, read only if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_topic: Option<String>,
+    /// Payload to publish, `{{value}}` and `{{feature}}` are replaced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_template: Option<String>,
+}
+
+impl MqttDevice {
+    fn state_topic<'a>(&'a self, feature: &'a Feature) -> &'a str {
+        meta_str(feature, "state_topic").unwrap_or(&self.state_topic)
+    }
+
+    fn command_topic<'a>(&'a self, feature: &'a Feature) -> Option<&'a str> {
+        meta_str(feature, "command_topic").or(self.command_topic.as_deref())
+    }
+
+    fn payload_template<'a>(&'a self, feature: &'a Feature) -> Option<&'a str> {
+        meta_str(feature, "payload_template").or(self.payload_template.as_deref())
+    }
+}
+
+fn meta_str<'a>(feature: &'a Feature, key: &str) -> Option<&'a str> {
+    feature.meta.get(key).and_then(|v| v.as_str())
+}
+
+fn pointer(feature: &Feature) -> &str {
+    meta_str(feature, "pointer").unwrap_or("")
+}
+
+/// Create or replace a MQTT device and its features and (re)start its task
+pub async fn save_device(
+    id: Option<String>,
+    name: String,
+    spec: MqttDevice,
+    features: Vec<Feature>,
+    task: &Task,
+) -> Result<Arc<Device>> {
+    for feature in &features {
+        let ptr = pointer(feature);
+        anyhow::ensure!(
+            ptr.is_empty() || ptr.starts_with('/'),
+            "Pointer {ptr:?} of feature {} must be empty or start with /",
+            feature.id
+        );
+    }
+
+    let mut tx = db::begin().await?;
+
+    let id = match id {
+        Some(id) => {
+            let existing = Device::load_by_id(&id, &mut tx).await?;
+
+            let TaskSpec::Mqtt(_) = existing.task_spec else {
+                anyhow::bail!("Device {id} is not a MQTT device");
+            };
+
+            id
+        }
+        None => crate::device::random_id("mqtt"),
+    };
+
+    let device = Device {
+        id,
+        name,
+        device_type: DeviceType::Hardware,
+        parent: None,
+        task_spec: TaskSpec::Mqtt(spec),
+    };
+
+    device.save(&mut tx).await?;
+    // Names given here come from the user, like a rename
+    Device::rename(&device.id, &device.name, &mut tx).await?;
+
+    for feature in &features {
+        feature.save(&device.id, &mut tx).await?;
+        Feature::rename(&device.id, &feature.id, &feature.name, &mut tx).await?;
+    }
+
+    let ids: Vec<&str> = features.iter().map(|f| f.id.as_str()).collect();
+    let deleted = Feature::delete_missing(&device.id, &ids, &mut tx).await?;
+
+    tx.commit().await?;
+
+    forget_features(task, &device.id, &deleted);
+
+    spawn_device_tasks(task, &device);
+
+    let device = Arc::new(device);
+
+    crate::device::notify_changed(device.clone());
+
+    Ok(device)
+}
+
+pub async fn mqtt_device(device_id: IString, task: Task) -> Result<()> {
+    let (device, features) = {
+        let mut conn = db::connection().await?;
+        let device = Device::load_by_id(device_id.into(), &mut conn).await?;
+        let features: Vec<Feature> = Feature::load_by_device(&device.id, &mut conn)
+            .try_filter(|f| future::ready(!f.virt))
+            .try_collect()
+            .await?;
+
+        (device, features)
+    };
+
+    let TaskSpec::Mqtt(spec) = &device.task_spec else {
+        anyhow::bail!("mqtt_device did not get a task spec it expected, this is a bug");
+    };
+
+    let incoming = async {
+        let mut incoming = mqtt::incoming();
+
+        let topics: BTreeSet<&str> = features
+            .iter()
+            .filter(|f| f.direction.can_read())
+            .map(|f| spec.state_topic(f))
+            .collect();
+
+        for topic in &topics {
+            mqtt::subscribe(MqttTopic {
+                server: spec.server.clone(),
+                topic: topic.to_string(),
+            });
+        }
+
+        while let Some((topic, data)) = incoming.next().await {
+            if !topics.contains(topic.as_str()) {
+                continue;
+            }
+
+            let payload = parse_payload(data);
+
+            let readers = features
+                .iter()
+                .filter(|f| f.direction.can_read() && spec.state_topic(f) == topic);
+
+            for feature in readers {
+                let key = ValueId::new(device_id, &feature.id);
+                value::set_current(key, read(feature, &payload));
+            }
+        }
+
+        Ok(())
+    };
+
+    let outgoing = async {
+        // What is queued still goes out when the device is stopped
+        let mut outgoing = task.until_stopped(value::push_subscribe_device(device_id));
+
+        while let Some((id, value)) = outgoing.next().await {
+            let fid: &str = id.feature.into();
+
+            let Some(feature) = features.iter().find(|f| f.id == fid) else {
+                warn!(
+                    "Could not find feature {fid} on mqtt push to {}",
+                    device.name
+                );
+                continue;
+            };
+
+            let Some(topic) = spec.command_topic(feature) else {
+                warn!("Feature {fid} of {} has no command topic", device.name);
+                continue;
+            };
+
+            let Some(payload) = render_payload(spec.payload_template(feature), feature, value)
+            else {
+                warn!(
+                    "Feature {fid} of {} needs value_on and value_off to be written",
+                    device.name
+                );
+                continue;
+            };
+
+            let sub = MqttTopic {
+                topic: topic.into(),
+                server: spec.server.clone(),
+            };
+
+            mqtt::publish(sub, payload);
+        }
+
+        Ok(())
+    };
+
+    // Either side ending means the device is broken, let the supervisor restart it
+    tokio::select! {
+        res = incoming => res,
+        res = outgoing => res,
+    }
+}
+
+/// Payloads that are not JSON, like `ON` or `21.5 C`, are treated as a string
+fn parse_payload(data: Bytes) -> Json {
+    match serde_json::de::from_reader(data.clone().reader()) {
+        Ok(json) => json,
+        Err(_) => Json::String(String::from_utf8_lossy(&data).into_owned()),
+    }
+}
+
+/// The value of a feature in a payload, text features keep payloads that look like numbers as text
+pub fn read(feature: &Feature, payload: &Json) -> Result<Json, String> {
+    let ptr = pointer(feature);
+
+    match (feature.kind, payload.pointer(ptr)) {
+        (ValueKind::String, Some(value @ (Json::Number(_) | Json::Bool(_)))) => {
+            Ok(Json::String(value.to_string()))
+        }
+        _ => feature.extract(payload, ptr),
+    }
+}
+
+/// What to publish to write a value, the template if there is one or the value at the pointer
+fn render_payload(template: Option<&str>, feature: &Feature, value: Json) -> Option<Vec<u8>> {
+    let raw = |value: Json| match value {
+        Json::String(s) => s,
+        other => other.to_string(),
+    };
+
+    let payload = match template {
+        Some(template) => template
+            .replace("{{feature}}", &feature.id)
+            .replace("{{value}}", &raw(feature.to_wire(value)?)),
+        None => raw(feature.to_payload(pointer(feature), value)?),
+    };
+
+    Some(payload.into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value as Json};
+
+    use super::{parse_payload, render_payload};
+    use crate::device::{Feature, ValueDirection, ValueKind};
+
+    fn feature(id: &str, kind: ValueKind, meta: Json) -> Feature {
+        Feature {
+            id: id.into(),
+            name: id.into(),
+            virt: false,
+            direction: ValueDirection::SourceSink,
+            kind,
+            meta,
+            automate: None,
+        }
+    }
+
+    #[test]
+    fn payloads() {
+        assert_eq!(parse_payload("ON".into()), json!("ON"));
+        assert_eq!(parse_payload("21.5".into()), json!(21.5));
+        assert_eq!(
+            parse_payload(r#"{"ENERGY":{"Power":12}}"#.into()),
+            json!({"ENERGY": {"Power": 12}})
+        );
+
+        let power = feature("power", ValueKind::String, json!({}));
+        assert_eq!(
+            render_payload(None, &power, json!("ON")),
+            Some(b"ON".to_vec())
+        );
+
+        let x = feature("x", ValueKind::Number, json!({ "pointer": "/color/x" }));
+        assert_eq!(
+            render_payload(None, &x, json!(0.3)),
+            Some(br#"{"color":{"x":0.3}}"#.to_vec())
+        );
+
+        let dimmer = feature("dimmer", ValueKind::Number, json!({}));
+        assert_eq!(
+            render_payload(Some("{{feature}} {{value}}"), &dimmer, json!(40)),
+            Some(b"dimmer 40".to_vec())
+        );
+    }
+
+    #[test]
+    fn booleans_need_wire_values() {
+        let relay = feature(
+            "relay",
+            ValueKind::Bool,
+            json!({ "value_on": "ON", "value_off": "OFF" }),
+        );
+        assert_eq!(
+            render_payload(None, &relay, json!(true)),
+            Some(b"ON".to_vec())
+        );
+
+        let unmapped = feature("unmapped", ValueKind::Bool, json!({}));
+        assert_eq!(render_payload(None, &unmapped, json!(true)), None);
+    }
+}
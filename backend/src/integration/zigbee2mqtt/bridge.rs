@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Buf;
+use futures::StreamExt;
+use serde_derive::Deserialize;
+use serde_json::{json, Value as Json};
+
+use crate::{
+    device::{ValueDirection, ValueKind},
+    io::mqtt::{self, MqttServerInfo, MqttTopic},
+    value::{self, ValueId},
+};
+
+/// How long to wait for the bridge to answer most requests
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Checking for firmware asks both the device and the firmware index
+pub const OTA_CHECK_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+/// Firmware updates only answer when they are done
+pub const OTA_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Features of the integration device describing the bridge
+pub fn bridge_features() -> Vec<crate::device::Feature> {
+    let source = |id: &str, name: &str, kind, meta| crate::device::Feature {
+        id: id.into(),
+        name: name.into(),
+        virt: false,
+        direction: ValueDirection::Source,
+        kind,
+        meta,
+        automate: None,
+    };
+
+    vec![
+        source(
+            "bridge_state",
+            "Bridge state",
+            ValueKind::State,
+            json!({ "possible": ["online", "offline"] }),
+        ),
+        source("permit_join", "Permit join", ValueKind::Bool, json!({})),
+        source("last_event", "Last event", ValueKind::String, json!({})),
+    ]
+}
+
+#[derive(Deserialize, Debug)]
+struct Response {
+    #[serde(default)]
+    data: Json,
+    status: String,
+    error: Option<String>,
+    transaction: Option<String>,
+}
+
+/// Send a request to `zigbee2mqtt/bridge/request/<endpoint>` and wait for the
+/// matching response, returns the data of the response
+pub async fn request(
+    server: &MqttServerInfo,
+    endpoint: &str,
+    mut payload: Json,
+    timeout: Duration,
+) -> Result<Json> {
+    let response_topic = format!("zigbee2mqtt/bridge/response/{endpoint}");
+
+    let [a]: [u64; 1] = rand::random();
+    let transaction = format!("bramble-{a:x}");
+
+    payload["transaction"] = json!(transaction);
+
+    // Listen before asking so we can not miss the answer
+    let mut incoming = mqtt::incoming();
+
+    mqtt::subscribe(MqttTopic {
+        server: server.clone(),
+        topic: response_topic.clone(),
+    });
+
+    mqtt::publish(
+        MqttTopic {
+            server: server.clone(),
+            topic: format!("zigbee2mqtt/bridge/request/{endpoint}"),
+        },
+        serde_json::to_vec(&payload)?,
+    );
+
+    let response = async {
+        while let Some((topic, data)) = incoming.next().await {
+            if topic != response_topic {
+                continue;
+            }
+
+            let Ok(response) = serde_json::de::from_reader::<_, Response>(data.reader()) else {
+                continue;
+            };
+
+            if response.transaction.as_deref() == Some(transaction.as_str()) {
+                return Some(response);
+            }
+        }
+
+        None
+    };
+
+    let response = tokio::time::timeout(timeout, response)
+        .await
+        .with_context(|| format!("zigbee2mqtt did not answer {endpoint} in time"))?
+        .context("MQTT connection went away")?;
+
+    match response.status.as_str() {
+        "ok" => Ok(response.data),
+        _ => anyhow::bail!(
+            "zigbee2mqtt {endpoint} failed: {}",
+            response.error.unwrap_or(response.status)
+        ),
+    }
+}
+
+/// Mirror the bridge state, permit join and events onto the integration device
+pub async fn bridge_status(parent: &str, server: &MqttServerInfo) -> Result<()> {
+    let state_id = ValueId::new(parent, "bridge_state");
+    let permit_id = ValueId::new(parent, "permit_join");
+    let event_id = ValueId::new(parent, "last_event");
+
+    let mut incoming = mqtt::incoming();
+
+    for topic in ["state", "info", "event"] {
+        mqtt::subscribe(MqttTopic {
+            server: server.clone(),
+            topic: format!("zigbee2mqtt/bridge/{topic}"),
+        });
+    }
+
+    while let Some((topic, data)) = incoming.next().await {
+        let Some(topic) = topic.strip_prefix("zigbee2mqtt/bridge/") else {
+            continue;
+        };
+
+        // Older versions publish the state as a bare string
+        let json: Json = serde_json::de::from_reader(data.clone().reader())
+            .unwrap_or_else(|_| Json::String(String::from_utf8_lossy(&data).into_owned()));
+
+        match topic {
+            "state" => {
+                let state = json.get("state").unwrap_or(&json);
+                value::set_current(state_id, Ok(state.clone()));
+            }
+            "info" => {
+                if let Some(permit) = json.get("permit_join") {
+                    value::set_current(permit_id, Ok(permit.clone()));
+                }
+            }
+            "event" => {
+                if let Some(event) = describe_event(&json) {
+                    value::set_current(event_id, Ok(json!(event)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// A human readable line for a `zigbee2mqtt/bridge/event` message
+fn describe_event(event: &Json) -> Option<String> {
+    let data = event.get("data")?;
+    let name = data
+        .get("friendly_name")
+        .or_else(|| data.get("ieee_address"))
+        .and_then(|n| n.as_str())
+        .unwrap_or("unknown device");
+
+    let text = match event.get("type")?.as_str()? {
+        "device_interview" => {
+            let status = data.get("status").and_then(|s| s.as_str()).unwrap_or("");
+            format!("Interview of {name} {status}")
+        }
+        "device_joined" => format!("{name} joined"),
+        "device_announce" => format!("{name} announced itself"),
+        "device_leave" => format!("{name} left"),
+        other => format!("{other} for {name}"),
+    };
+
+    Some(text)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    #[test]
+    fn events() {
+        let interview = json!({
+            "type": "device_interview",
+            "data": {
+                "friendly_name": "0x00158d0001dc126a",
+                "status": "successful",
+                "ieee_address": "0x00158d0001dc126a",
+            },
+        });
+
+        assert_eq!(
+            super::describe_event(&interview).unwrap(),
+            "Interview of 0x00158d0001dc126a successful"
+        );
+
+        let leave = json!({ "type": "device_leave", "data": { "ieee_address": "0x01" } });
+
+        assert_eq!(super::describe_event(&leave).unwrap(), "0x01 left");
+        assert!(super::describe_event(&json!({ "type": "device_leave" })).is_none());
+    }
+}
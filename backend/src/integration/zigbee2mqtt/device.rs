@@ -16,6 +16,7 @@ pub enum DeviceType {
     Unknown,
 }
 
+#[allow(unused)]
 #[derive(Deserialize, Debug)]
 pub struct Device {
     pub ieee_address: String,
@@ -64,6 +65,7 @@ impl Device {
     }
 }
 
+#[allow(unused)]
 #[derive(Deserialize, Debug)]
 pub struct Definition {
     pub model: String,
@@ -74,170 +76,307 @@ pub struct Definition {
 
 impl Definition {
     pub fn to_feature(&self) -> Vec<crate::device::Feature> {
-        let mut stack = self.exposes.iter().collect::<Vec<_>>();
         let mut out = vec![];
 
-        while let Some(feature) = stack.pop() {
-            match feature {
-                Feature::Binary {
-                    name,
-                    property,
-                    value_on,
-                    value_off,
-                    access,
-                    ..
-                } => {
-                    let direction = match access.try_into() {
-                        Ok(dir) => dir,
-                        Err(e) => {
-                            error!("{e:?}");
-                            continue;
-                        }
-                    };
-
-                    out.push(crate::device::Feature {
-                        name: clean_up_name(name),
-                        id: property.clone(),
-                        kind: ValueKind::Bool,
-                        direction,
-                        meta: json!({
-                            "value_off": value_off.clone(),
-                            "value_on": value_on.clone(),
-                        }),
-                        automate: None,
-                        virt: false,
-                    })
+        for expose in &self.exposes {
+            expose.collect(&Path::default(), &mut out);
+        }
+
+        out
+    }
+}
+
+/// Where the properties of a composite end up, ids are prefixed with the composite
+/// property and the pointer goes into the nested object
+#[derive(Default, Clone)]
+struct Path {
+    id: String,
+    pointer: String,
+    name: String,
+}
+
+impl Path {
+    fn child(&self, property: &str, name: &str) -> Path {
+        Path {
+            id: format!("{}{property}_", self.id),
+            pointer: format!("{}/{property}", self.pointer),
+            name: format!("{}{} ", self.name, clean_up_name(name)),
+        }
+    }
+
+    fn feature(
+        &self,
+        name: &str,
+        property: &str,
+        endpoint: &Option<String>,
+        access: &Access,
+        kind: ValueKind,
+        mut meta: serde_json::Value,
+    ) -> Option<crate::device::Feature> {
+        let direction = match access.try_into() {
+            Ok(dir) => dir,
+            Err(e) => {
+                error!("{e:?}");
+                return None;
+            }
+        };
+
+        let mut name = format!("{}{}", self.name, clean_up_name(name));
+
+        if !self.pointer.is_empty() {
+            meta["pointer"] = json!(format!("{}/{property}", self.pointer));
+        }
+
+        // Multi gang devices expose the same feature once per endpoint
+        if let Some(endpoint) = endpoint {
+            meta["endpoint"] = json!(endpoint);
+            name = format!("{name} ({endpoint})");
+        }
+
+        Some(crate::device::Feature {
+            id: format!("{}{property}", self.id),
+            name,
+            direction,
+            kind,
+            meta: readable(meta, access),
+            automate: None,
+            virt: false,
+        })
+    }
+}
+
+impl Feature {
+    /// Add the features of an expose to `out`, composites add themselves as an
+    /// object and each of their properties
+    fn collect(&self, path: &Path, out: &mut Vec<crate::device::Feature>) {
+        let feature = match self {
+            Feature::Binary {
+                name,
+                property,
+                value_on,
+                value_off,
+                value_toggle,
+                access,
+                endpoint,
+            } => {
+                let mut meta = json!({
+                    "value_off": value_off.clone(),
+                    "value_on": value_on.clone(),
+                });
+
+                if let Some(toggle) = value_toggle {
+                    meta["value_toggle"] = toggle.clone();
                 }
 
-                Feature::Numeric {
-                    name,
-                    property,
-                    unit,
-                    access,
-                    value_min,
-                    value_max,
-                    ..
-                } => {
-                    let direction = match access.try_into() {
-                        Ok(dir) => dir,
-                        Err(e) => {
-                            error!("{e:?}");
-                            continue;
-                        }
-                    };
-
-                    let mut meta = serde_json::Map::new();
-
-                    if let Some(unit) = unit {
-                        meta.insert("unit".into(), json!(unit.clone()));
-                    }
+                path.feature(name, property, endpoint, access, ValueKind::Bool, meta)
+            }
+
+            Feature::Numeric {
+                name,
+                property,
+                unit,
+                access,
+                value_min,
+                value_max,
+                value_step,
+                endpoint,
+            } => {
+                let mut meta = serde_json::Map::new();
+
+                if let Some(unit) = unit {
+                    meta.insert("unit".into(), json!(unit.clone()));
+                }
 
-                    if let Some(min) = value_min {
-                        meta.insert("min".into(), json!(min));
-                    }
+                if let Some(min) = value_min {
+                    meta.insert("min".into(), json!(min));
+                }
 
-                    if let Some(max) = value_max {
-                        meta.insert("max".into(), json!(max));
-                    }
+                if let Some(max) = value_max {
+                    meta.insert("max".into(), json!(max));
+                }
 
-                    out.push(crate::device::Feature {
-                        name: clean_up_name(name),
-                        id: property.clone(),
-                        direction,
-                        kind: ValueKind::Number,
-                        meta: meta.into(),
-                        automate: None,
-                        virt: false,
-                    })
+                if let Some(step) = value_step {
+                    meta.insert("step".into(), json!(step));
                 }
 
-                Feature::Enum {
+                path.feature(
                     name,
                     property,
-                    values,
+                    endpoint,
                     access,
-                } => {
-                    let direction = match access.try_into() {
-                        Ok(dir) => dir,
-                        Err(e) => {
-                            error!("{e:?}");
-                            continue;
-                        }
-                    };
-
-                    out.push(crate::device::Feature {
-                        name: clean_up_name(name),
-                        id: property.clone(),
-                        direction,
-                        kind: ValueKind::State,
-                        meta: json!({
-                           "possible": values.clone(),
-                        }),
-                        automate: None,
-                        virt: false,
-                    })
+                    ValueKind::Number,
+                    meta.into(),
+                )
+            }
+
+            Feature::Enum {
+                name,
+                property,
+                values,
+                access,
+                endpoint,
+            } => path.feature(
+                name,
+                property,
+                endpoint,
+                access,
+                ValueKind::State,
+                json!({ "possible": values.clone() }),
+            ),
+
+            Feature::Text {
+                name,
+                property,
+                access,
+                endpoint,
+            } => path.feature(
+                name,
+                property,
+                endpoint,
+                access,
+                ValueKind::String,
+                json!({}),
+            ),
+
+            Feature::List {
+                name,
+                property,
+                access,
+                item_type,
+                endpoint,
+            } => {
+                // Older versions name the item type, newer ones describe it as a feature
+                let item = item_type
+                    .get("type")
+                    .unwrap_or(item_type)
+                    .as_str()
+                    .unwrap_or("unknown");
+
+                let mut meta = json!({ "item_type": item });
+
+                if let Some(items) = list_item_schema(item_type) {
+                    meta["items"] = items;
                 }
 
-                Feature::Text {
-                    name,
-                    property,
-                    access,
-                } => {
-                    let direction = match access.try_into() {
-                        Ok(dir) => dir,
-                        Err(e) => {
-                            error!("{e:?}");
-                            continue;
-                        }
-                    };
-
-                    out.push(crate::device::Feature {
-                        name: clean_up_name(name),
-                        id: property.clone(),
-                        direction,
-                        kind: ValueKind::String,
-                        meta: json!({}),
-                        automate: None,
-                        virt: false,
-                    })
+                path.feature(name, property, endpoint, access, ValueKind::Array, meta)
+            }
+
+            Feature::Composite {
+                name,
+                property,
+                features,
+                access,
+                endpoint,
+            } => {
+                let child = path.child(property, name);
+
+                for feature in features {
+                    feature.collect(&child, out);
                 }
 
-                Feature::List {
-                    name,
-                    property,
-                    access,
-                    ..
-                } => {
-                    let direction = match access.try_into() {
-                        Ok(dir) => dir,
-                        Err(e) => {
-                            error!("{e:?}");
-                            continue;
-                        }
-                    };
-
-                    out.push(crate::device::Feature {
-                        name: clean_up_name(name),
-                        id: property.clone(),
-                        direction,
-                        kind: ValueKind::Number,
-                        meta: json!({}),
-                        automate: None,
-                        virt: false,
-                    })
+                let meta = self.schema().unwrap_or_else(|| json!({}));
+
+                access.as_ref().and_then(|access| {
+                    path.feature(name, property, endpoint, access, ValueKind::Object, meta)
+                })
+            }
+
+            Feature::Light { features }
+            | Feature::Switch { features }
+            | Feature::Fan { features }
+            | Feature::Cover { features }
+            | Feature::Lock { features }
+            | Feature::Climate { features } => {
+                for feature in features {
+                    feature.collect(path, out);
+                }
+
+                None
+            }
+
+            Feature::Unknown => None,
+        };
+
+        out.extend(feature);
+    }
+
+    /// The schema validation uses for this expose inside an object or array, binary
+    /// values are left out as they are only turned into a boolean at the top level
+    fn schema(&self) -> Option<serde_json::Value> {
+        let schema = match self {
+            Feature::Numeric {
+                unit,
+                value_min,
+                value_max,
+                value_step,
+                ..
+            } => {
+                let mut schema = json!({ "kind": ValueKind::Number });
+
+                for (key, value) in [("min", value_min), ("max", value_max), ("step", value_step)] {
+                    if let Some(value) = value {
+                        schema[key] = json!(value);
+                    }
                 }
 
-                Feature::Composite { features, .. } => stack.extend(features),
-                Feature::Light { features } => stack.extend(features),
-                Feature::Switch { features } => stack.extend(features),
-                Feature::Fan { features } => stack.extend(features),
-                Feature::Cover { features } => stack.extend(features),
-                Feature::Lock { features } => stack.extend(features),
-                Feature::Climate { features } => stack.extend(features),
-            };
+                if let Some(unit) = unit {
+                    schema["unit"] = json!(unit);
+                }
+
+                schema
+            }
+            Feature::Enum { values, .. } => json!({
+                "kind": ValueKind::State,
+                "possible": values,
+            }),
+            Feature::Text { .. } => json!({ "kind": ValueKind::String }),
+            Feature::Composite { features, .. } => {
+                let fields: serde_json::Map<String, serde_json::Value> = features
+                    .iter()
+                    .filter_map(|f| Some((f.property()?.to_string(), f.schema()?)))
+                    .collect();
+
+                json!({ "kind": ValueKind::Object, "fields": fields })
+            }
+            Feature::List { item_type, .. } => {
+                let mut schema = json!({ "kind": ValueKind::Array });
+
+                if let Some(items) = list_item_schema(item_type) {
+                    schema["items"] = items;
+                }
+
+                schema
+            }
+            _ => return None,
+        };
+
+        Some(schema)
+    }
+
+    fn property(&self) -> Option<&str> {
+        match self {
+            Feature::Binary { property, .. }
+            | Feature::Numeric { property, .. }
+            | Feature::Enum { property, .. }
+            | Feature::Text { property, .. }
+            | Feature::Composite { property, .. }
+            | Feature::List { property, .. } => Some(property),
+            _ => None,
         }
+    }
+}
 
-        out
+/// Items of a list are described as an expose without a property
+fn list_item_schema(item_type: &serde_json::Value) -> Option<serde_json::Value> {
+    match item_type {
+        serde_json::Value::String(kind) => match kind.as_str() {
+            "number" => Some(json!({ "kind": ValueKind::Number })),
+            "text" => Some(json!({ "kind": ValueKind::String })),
+            _ => None,
+        },
+        item => serde_json::from_value::<Feature>(item.clone())
+            .ok()?
+            .schema(),
     }
 }
 
@@ -268,7 +407,6 @@ impl Access {
         self.0 & mask == 0b010
     }
 
-    #[allow(unused)]
     /// Can you read the steaful state of this feature
     pub fn read(&self) -> bool {
         let mask = 0b100;
@@ -300,16 +438,18 @@ pub enum Feature {
         value_off: serde_json::Value,
         value_toggle: Option<serde_json::Value>,
         access: Access,
+        endpoint: Option<String>,
     },
     #[serde(rename = "numeric")]
     Numeric {
         name: String,
         property: String,
-        value_min: Option<i32>,
-        value_max: Option<i32>,
-        value_step: Option<i32>,
+        value_min: Option<serde_json::Number>,
+        value_max: Option<serde_json::Number>,
+        value_step: Option<serde_json::Number>,
         unit: Option<String>,
         access: Access,
+        endpoint: Option<String>,
     },
     #[serde(rename = "enum")]
     Enum {
@@ -317,25 +457,30 @@ pub enum Feature {
         property: String,
         values: Vec<String>,
         access: Access,
+        endpoint: Option<String>,
     },
     #[serde(rename = "text")]
     Text {
         name: String,
         property: String,
         access: Access,
+        endpoint: Option<String>,
     },
     #[serde(rename = "composite")]
     Composite {
         name: String,
         property: String,
         features: Vec<Feature>,
+        access: Option<Access>,
+        endpoint: Option<String>,
     },
     #[serde(rename = "list")]
     List {
         name: String,
         property: String,
-        item_type: Box<Feature>,
+        item_type: serde_json::Value,
         access: Access,
+        endpoint: Option<String>,
     },
     #[serde(rename = "light")]
     Light { features: Vec<Feature> },
@@ -349,6 +494,18 @@ pub enum Feature {
     Lock { features: Vec<Feature> },
     #[serde(rename = "climate")]
     Climate { features: Vec<Feature> },
+    /// Exposes added in newer versions should not break the whole device list
+    #[serde(other)]
+    Unknown,
+}
+
+/// Remember if a feature can be asked for its value with a `/get`
+fn readable(mut meta: serde_json::Value, access: &Access) -> serde_json::Value {
+    if access.read() {
+        meta["readable"] = json!(true);
+    }
+
+    meta
 }
 
 fn clean_up_name(name: &str) -> String {
@@ -381,32 +538,292 @@ mod test {
     fn test_access() {
         let publish = super::Access(0b001);
 
-        assert_eq!(publish.published(), true);
-        assert_eq!(publish.write(), false);
-        assert_eq!(publish.read(), false);
+        assert!(publish.published());
+        assert!(!publish.write());
+        assert!(!publish.read());
 
         let write = super::Access(0b010);
 
-        assert_eq!(write.published(), false);
-        assert_eq!(write.write(), true);
-        assert_eq!(write.read(), false);
+        assert!(!write.published());
+        assert!(write.write());
+        assert!(!write.read());
 
         let read = super::Access(0b100);
 
-        assert_eq!(read.published(), false);
-        assert_eq!(read.write(), false);
-        assert_eq!(read.read(), true);
+        assert!(!read.published());
+        assert!(!read.write());
+        assert!(read.read());
 
         let all = super::Access(0b111);
 
-        assert_eq!(all.published(), true);
-        assert_eq!(all.write(), true);
-        assert_eq!(all.read(), true);
+        assert!(all.published());
+        assert!(all.write());
+        assert!(all.read());
 
         let rw = super::Access(0b011);
 
-        assert_eq!(rw.published(), true);
-        assert_eq!(rw.write(), true);
-        assert_eq!(rw.read(), false);
+        assert!(rw.published());
+        assert!(rw.write());
+        assert!(!rw.read());
+    }
+
+    /// Trimmed `zigbee2mqtt/bridge/devices` entries of a few vendors
+    const DEVICES: &str = r#"[
+        {
+            "ieee_address": "0x001788010bcd1234",
+            "type": "Router",
+            "network_address": 4660,
+            "supported": true,
+            "friendly_name": "Hallway light",
+            "power_source": "Mains (single phase)",
+            "model_id": "LCT015",
+            "interviewing": false,
+            "interview_completed": true,
+            "definition": {
+                "model": "9290012573A",
+                "vendor": "Philips",
+                "description": "Hue white and color ambiance E26/E27/E14",
+                "exposes": [
+                    {
+                        "type": "light",
+                        "features": [
+                            {"type": "binary", "name": "state", "property": "state", "access": 7, "value_on": "ON", "value_off": "OFF", "value_toggle": "TOGGLE"},
+                            {"type": "numeric", "name": "brightness", "property": "brightness", "access": 7, "value_min": 0, "value_max": 254},
+                            {"type": "numeric", "name": "color_temp", "property": "color_temp", "access": 7, "unit": "mired", "value_min": 150, "value_max": 500,
+                             "presets": [{"name": "coolest", "value": 150, "description": "Coolest temperature supported"}]},
+                            {"type": "composite", "name": "color_xy", "property": "color", "access": 7, "features": [
+                                {"type": "numeric", "name": "x", "property": "x", "access": 7},
+                                {"type": "numeric", "name": "y", "property": "y", "access": 7}
+                            ]},
+                            {"type": "composite", "name": "color_hs", "property": "color", "access": 7, "features": [
+                                {"type": "numeric", "name": "hue", "property": "hue", "access": 7},
+                                {"type": "numeric", "name": "saturation", "property": "saturation", "access": 7}
+                            ]}
+                        ]
+                    },
+                    {"type": "enum", "name": "effect", "property": "effect", "access": 2, "values": ["blink", "breathe", "okay"]},
+                    {"type": "numeric", "name": "linkquality", "property": "linkquality", "access": 1, "unit": "lqi", "value_min": 0, "value_max": 255}
+                ]
+            }
+        },
+        {
+            "ieee_address": "0xa4c138aabbcc0011",
+            "type": "Router",
+            "network_address": 1234,
+            "supported": true,
+            "friendly_name": "Kitchen switch",
+            "interviewing": false,
+            "interview_completed": true,
+            "definition": {
+                "model": "TS0002",
+                "vendor": "TuYa",
+                "description": "2 gang switch module",
+                "exposes": [
+                    {"type": "switch", "endpoint": "l1", "features": [
+                        {"type": "binary", "name": "state", "property": "state_l1", "endpoint": "l1", "access": 7, "value_on": "ON", "value_off": "OFF", "value_toggle": "TOGGLE"}
+                    ]},
+                    {"type": "switch", "endpoint": "l2", "features": [
+                        {"type": "binary", "name": "state", "property": "state_l2", "endpoint": "l2", "access": 7, "value_on": "ON", "value_off": "OFF", "value_toggle": "TOGGLE"}
+                    ]},
+                    {"type": "enum", "name": "power_on_behavior", "property": "power_on_behavior", "access": 7, "values": ["off", "previous", "on"]}
+                ]
+            }
+        },
+        {
+            "ieee_address": "0x00158d0001dc126a",
+            "type": "EndDevice",
+            "network_address": 29314,
+            "supported": true,
+            "friendly_name": "Bathroom climate",
+            "power_source": "Battery",
+            "interviewing": false,
+            "interview_completed": true,
+            "definition": {
+                "model": "WSDCGQ11LM",
+                "vendor": "Aqara",
+                "description": "Temperature, humidity and pressure sensor",
+                "exposes": [
+                    {"type": "numeric", "name": "battery", "property": "battery", "access": 1, "unit": "%", "value_min": 0, "value_max": 100},
+                    {"type": "numeric", "name": "temperature", "property": "temperature", "access": 1, "unit": "°C"},
+                    {"type": "numeric", "name": "humidity", "property": "humidity", "access": 1, "unit": "%"},
+                    {"type": "numeric", "name": "pressure", "property": "pressure", "access": 1, "unit": "hPa"}
+                ]
+            }
+        },
+        {
+            "ieee_address": "0x84fd27fffe6a0b1c",
+            "type": "EndDevice",
+            "network_address": 51311,
+            "supported": true,
+            "friendly_name": "Living room radiator",
+            "power_source": "Battery",
+            "interviewing": false,
+            "interview_completed": true,
+            "definition": {
+                "model": "TS0601_thermostat",
+                "vendor": "TuYa",
+                "description": "Radiator valve with thermostat",
+                "exposes": [
+                    {"type": "climate", "features": [
+                        {"type": "numeric", "name": "current_heating_setpoint", "property": "current_heating_setpoint", "access": 3, "unit": "°C", "value_min": 5, "value_max": 35, "value_step": 0.5},
+                        {"type": "numeric", "name": "local_temperature_calibration", "property": "local_temperature_calibration", "access": 3, "unit": "°C", "value_min": -9.5, "value_max": 9.5, "value_step": 0.5},
+                        {"type": "enum", "name": "system_mode", "property": "system_mode", "access": 3, "values": ["heat", "auto", "off"]}
+                    ]},
+                    {"type": "list", "name": "schedule", "property": "schedule", "access": 3, "item_type": {
+                        "type": "composite", "name": "dayTime", "property": "dayTime", "access": 3, "features": [
+                            {"type": "numeric", "name": "hour", "property": "hour", "access": 3, "value_min": 0, "value_max": 23},
+                            {"type": "numeric", "name": "temperature", "property": "temperature", "access": 3, "unit": "°C"}
+                        ]
+                    }},
+                    {"type": "list", "name": "programming", "property": "programming", "access": 1, "item_type": "number"},
+                    {"type": "future_expose", "name": "something", "property": "something", "access": 1}
+                ]
+            }
+        },
+        {
+            "ieee_address": "0x000d6ffffe1e2a3b",
+            "type": "EndDevice",
+            "network_address": 15001,
+            "supported": true,
+            "friendly_name": "Remote",
+            "power_source": "Battery",
+            "interviewing": false,
+            "interview_completed": true,
+            "definition": {
+                "model": "E1524/E1810",
+                "vendor": "IKEA",
+                "description": "TRADFRI remote control",
+                "exposes": [
+                    {"type": "numeric", "name": "battery", "property": "battery", "access": 1, "unit": "%", "value_min": 0, "value_max": 100},
+                    {"type": "enum", "name": "action", "property": "action", "access": 1, "values": ["arrow_left_click", "toggle", "brightness_up_click"]}
+                ]
+            }
+        }
+    ]"#;
+
+    fn features_of(name: &str) -> Vec<crate::device::Feature> {
+        let devices: Vec<super::Device> = serde_json::from_str(DEVICES).unwrap();
+        let server =
+            crate::io::mqtt::MqttServerInfo::new("localhost".into(), 1883, None, None, None);
+
+        let device = devices
+            .into_iter()
+            .find(|d| d.friendly_name == name)
+            .unwrap();
+
+        device
+            .into_device("z2mqtt:localhost:1883", server)
+            .unwrap()
+            .1
+    }
+
+    fn find<'a>(features: &'a [crate::device::Feature], id: &str) -> &'a crate::device::Feature {
+        features.iter().find(|f| f.id == id).unwrap()
+    }
+
+    #[test]
+    fn composite_light() {
+        use crate::device::{ValueDirection, ValueKind};
+        use serde_json::json;
+
+        let features = features_of("Hallway light");
+
+        let state = find(&features, "state");
+        assert_eq!(state.meta["value_toggle"], json!("TOGGLE"));
+        assert_eq!(state.meta["readable"], json!(true));
+
+        let color = find(&features, "color");
+        assert_eq!(color.kind, ValueKind::Object);
+        assert!(color.meta.get("pointer").is_none());
+
+        let x = find(&features, "color_x");
+        assert_eq!(x.name, "Color xy X");
+        assert_eq!(x.meta["pointer"], json!("/color/x"));
+
+        let payload = json!({"state": "ON", "color": {"x": 0.31, "y": 0.32}});
+        assert_eq!(x.extract(&payload, "/color/x"), Ok(json!(0.31)));
+        assert_eq!(
+            color.extract(&payload, "/color"),
+            Ok(json!({"x": 0.31, "y": 0.32}))
+        );
+        assert_eq!(color.meta["fields"]["x"]["kind"], json!("Number"));
+        assert!(color.validate(&json!({"x": "left"})).is_err());
+        // Devices report more than the composite exposes
+        assert!(color.validate(&json!({"x": 0.2, "hue": 30})).is_ok());
+        assert_eq!(
+            x.to_payload("/color/x", json!(0.5)),
+            Some(json!({"color": {"x": 0.5}}))
+        );
+
+        assert_eq!(
+            find(&features, "color_hue").meta["pointer"],
+            json!("/color/hue")
+        );
+
+        // Only the first composite writing to the same property is kept
+        assert_eq!(features.iter().filter(|f| f.id == "color").count(), 1);
+
+        let effect = find(&features, "effect");
+        assert_eq!(effect.direction, ValueDirection::Sink);
+        assert!(effect.meta.get("readable").is_none());
+    }
+
+    #[test]
+    fn endpoints() {
+        use serde_json::json;
+
+        let features = features_of("Kitchen switch");
+
+        let l1 = find(&features, "state_l1");
+        assert_eq!(l1.name, "State (l1)");
+        assert_eq!(l1.meta["endpoint"], json!("l1"));
+
+        assert_eq!(find(&features, "state_l2").name, "State (l2)");
+        assert_eq!(
+            find(&features, "power_on_behavior").name,
+            "Power on behavior"
+        );
+    }
+
+    #[test]
+    fn ranges_and_lists() {
+        use crate::device::ValueKind;
+        use serde_json::json;
+
+        let features = features_of("Living room radiator");
+
+        let setpoint = find(&features, "current_heating_setpoint");
+        assert_eq!(setpoint.meta["step"], json!(0.5));
+        assert_eq!(setpoint.meta["min"], json!(5));
+
+        let calibration = find(&features, "local_temperature_calibration");
+        assert_eq!(calibration.meta["min"], json!(-9.5));
+
+        let schedule = find(&features, "schedule");
+        assert_eq!(schedule.kind, ValueKind::Array);
+        assert_eq!(schedule.meta["item_type"], json!("composite"));
+        assert_eq!(
+            schedule.validate(&json!([{"hour": 6, "temperature": 21}])),
+            Ok(json!([{"hour": 6, "temperature": 21}]))
+        );
+        assert!(schedule.validate(&json!(21)).is_err());
+        assert_eq!(schedule.meta["items"]["fields"]["hour"]["max"], json!(23));
+        assert!(schedule
+            .validate(&json!([{"hour": "six", "temperature": 21}]))
+            .is_err());
+
+        assert_eq!(
+            find(&features, "programming").meta["item_type"],
+            json!("number")
+        );
+
+        // The unknown expose is skipped instead of failing the whole list
+        assert_eq!(features.len(), 5);
+
+        let sensor = features_of("Bathroom climate");
+        assert_eq!(find(&sensor, "temperature").meta["unit"], json!("°C"));
+
+        let remote = features_of("Remote");
+        assert_eq!(find(&remote, "action").kind, ValueKind::State);
     }
 }
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use serde_derive::Deserialize;
+use serde_json::json;
+
+use crate::{
+    device::{Feature, TaskSpec, ValueDirection, ValueKind},
+    io::mqtt::{MqttServerInfo, MqttTopic},
+};
+
+/// A group from `zigbee2mqtt/bridge/groups`
+#[derive(Deserialize, Debug)]
+pub struct Group {
+    pub id: u32,
+    pub friendly_name: String,
+    #[serde(default)]
+    pub members: Vec<Member>,
+    #[serde(default)]
+    pub scenes: Vec<Scene>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Member {
+    pub ieee_address: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Scene {
+    pub id: u32,
+    pub name: String,
+}
+
+impl Group {
+    /// The device id of a group, group ids are only unique within one bridge
+    pub fn device_id(parent: &str, id: u32) -> String {
+        format!("{parent}:group:{id}")
+    }
+
+    /// Turn a group into a device with the features its members can be controlled with,
+    /// `exposes` are the features of the devices on the network by ieee address
+    pub fn to_device(
+        &self,
+        parent: &str,
+        server: MqttServerInfo,
+        exposes: &HashMap<String, Vec<Feature>>,
+    ) -> (crate::device::Device, Vec<Feature>) {
+        let topic = format!("zigbee2mqtt/{}", self.friendly_name);
+
+        // Sensors only make sense per device, a group is for switching members together
+        let features = self
+            .members
+            .iter()
+            .filter_map(|m| exposes.get(&m.ieee_address))
+            .flatten()
+            .filter(|f| f.direction != ValueDirection::Source)
+            .unique_by(|f| f.id.clone())
+            .map(|f| Feature {
+                id: f.id.clone(),
+                name: f.name.clone(),
+                virt: false,
+                direction: f.direction,
+                kind: f.kind,
+                meta: f.meta.clone(),
+                automate: None,
+            })
+            .chain(scene_features(&self.scenes))
+            .collect();
+
+        let device = crate::device::Device {
+            id: Group::device_id(parent, self.id),
+            name: self.friendly_name.clone(),
+            device_type: crate::device::DeviceType::Hardware,
+            parent: Some(parent.into()),
+            task_spec: TaskSpec::Zigbee2MqttGroup(MqttTopic { server, topic }),
+        };
+
+        (device, features)
+    }
+}
+
+/// Scenes are stored on the members, recalling or storing one takes the scene id
+fn scene_features(scenes: &[Scene]) -> Vec<Feature> {
+    let scenes: Vec<_> = scenes
+        .iter()
+        .map(|s| json!({ "id": s.id, "name": s.name }))
+        .collect();
+
+    let sink = |id: &str, name: &str| Feature {
+        id: id.into(),
+        name: name.into(),
+        virt: false,
+        direction: ValueDirection::Sink,
+        kind: ValueKind::Number,
+        meta: json!({ "min": 0, "max": 255, "step": 1, "scenes": scenes }),
+        automate: None,
+    };
+
+    vec![
+        sink("scene_recall", "Recall scene"),
+        sink("scene_store", "Store scene"),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::device::{Feature, TaskSpec, ValueDirection, ValueKind};
+
+    fn feature(id: &str, direction: ValueDirection) -> Feature {
+        Feature {
+            id: id.into(),
+            name: id.into(),
+            virt: false,
+            direction,
+            kind: ValueKind::Number,
+            meta: json!({}),
+            automate: None,
+        }
+    }
+
+    #[test]
+    fn merged_exposes() {
+        let groups = r#"[{
+            "id": 3,
+            "friendly_name": "Living room",
+            "description": null,
+            "members": [
+                {"ieee_address": "0x01", "endpoint": 11},
+                {"ieee_address": "0x02", "endpoint": 1},
+                {"ieee_address": "0x03", "endpoint": 1}
+            ],
+            "scenes": [{"id": 1, "name": "Movie"}]
+        }]"#;
+
+        let mut groups: Vec<super::Group> = serde_json::from_str(groups).unwrap();
+
+        let mut exposes = HashMap::new();
+        exposes.insert(
+            "0x01".to_string(),
+            vec![
+                feature("state", ValueDirection::SourceSink),
+                feature("brightness", ValueDirection::SourceSink),
+                feature("linkquality", ValueDirection::Source),
+            ],
+        );
+        exposes.insert(
+            "0x02".to_string(),
+            vec![
+                feature("state", ValueDirection::SourceSink),
+                feature("color_temp", ValueDirection::SourceSink),
+            ],
+        );
+
+        let server =
+            crate::io::mqtt::MqttServerInfo::new("localhost".into(), 1883, None, None, None);
+
+        let (device, features) = groups.remove(0).to_device("z2mqtt:a:1", server, &exposes);
+
+        assert_eq!(device.id, "z2mqtt:a:1:group:3");
+        assert!(matches!(
+            device.task_spec,
+            TaskSpec::Zigbee2MqttGroup(ref t) if t.topic == "zigbee2mqtt/Living room"
+        ));
+
+        let ids: Vec<&str> = features.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "state",
+                "brightness",
+                "color_temp",
+                "scene_recall",
+                "scene_store"
+            ]
+        );
+
+        let recall = features.iter().find(|f| f.id == "scene_recall").unwrap();
+        assert_eq!(recall.direction, ValueDirection::Sink);
+        assert_eq!(recall.meta["scenes"], json!([{"id": 1, "name": "Movie"}]));
+    }
+}
@@ -1,6 +1,12 @@
+pub mod bridge;
 mod device;
+mod group;
 
-use std::{future, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future,
+    sync::Arc,
+};
 
 use anyhow::Result;
 
@@ -10,16 +16,19 @@ use futures::{StreamExt, TryStreamExt};
 use futures_concurrency::future::Join;
 use serde_json::Value as Json;
 use sqlx::SqliteConnection;
-use tracing::{error, warn};
+use tracing::{debug, error, warn};
 
+use super::{connection_features, connection_status};
 use crate::{
     db,
-    device::{spawn_device_tasks, TaskSpec, ValueKind},
+    device::{spawn_device_tasks, TaskSpec, ValueDirection, ValueKind},
     io::mqtt::{self, MqttServerInfo, MqttTopic},
     strings::IString,
     task::Task,
     value::{self, ValueId},
 };
+use bridge::{bridge_features, bridge_status};
+use group::Group;
 
 pub async fn create_integration_device(
     server_info: MqttServerInfo,
@@ -38,6 +47,10 @@ pub async fn create_integration_device(
 
     device.save(conn).await?;
 
+    for feature in connection_features().into_iter().chain(bridge_features()) {
+        feature.save(&device.id, conn).await?;
+    }
+
     spawn_device_tasks(task, &device);
 
     let device = Arc::new(device);
@@ -47,77 +60,307 @@ pub async fn create_integration_device(
     Ok(device)
 }
 
+/// The server behind a zigbee2mqtt integration device or one of its devices
+pub async fn server_of(device_id: &str, conn: &mut SqliteConnection) -> Result<MqttServerInfo> {
+    let device = crate::device::Device::load_by_id(device_id, conn).await?;
+
+    match device.task_spec {
+        TaskSpec::Zigbee2Mqtt(server) => Ok(server),
+        TaskSpec::Zigbee2MqttDevice(topic) | TaskSpec::Zigbee2MqttGroup(topic) => Ok(topic.server),
+        _ => anyhow::bail!("{device_id} is not part of a zigbee2mqtt integration"),
+    }
+}
+
 pub async fn zigbee2mqtt_update(
     (parent, server): (String, MqttServerInfo),
     task: Task,
 ) -> Result<()> {
-    let mut channel = mqtt::incoming();
+    {
+        // Integration devices created before the features existed need them too
+        let _busy = task.busy();
+        let mut tx = db::begin().await?;
 
-    mqtt::subscribe(MqttTopic {
-        topic: "zigbee2mqtt/bridge/devices".into(),
-        server: server.clone(),
-    });
+        for feature in connection_features().into_iter().chain(bridge_features()) {
+            feature.save(&parent, &mut tx).await?;
+        }
 
-    while let Some((topic, data)) = channel.next().await {
-        if topic == "zigbee2mqtt/bridge/devices" {
-            let reader = data.reader();
+        tx.commit().await?;
+    }
 
-            let device_spec: Vec<Device> = serde_json::de::from_reader(reader)?;
+    // The first part to finish or fail ends the integration task so it can be restarted
+    tokio::select! {
+        res = bridge_devices(&parent, &server, &task) => res,
+        res = connection_status(&parent, &server) => res,
+        res = bridge_status(&parent, &server) => res,
+    }
+}
 
-            let iter = device_spec
-                .into_iter()
-                .filter_map(|d| d.into_device(&parent, server.clone()));
+async fn bridge_devices(parent: &str, server: &MqttServerInfo, task: &Task) -> Result<()> {
+    let mut channel = mqtt::incoming_lagged();
 
-            for (device, features) in iter {
-                let mut tx = db::begin().await?;
+    for topic in ["devices", "groups"] {
+        mqtt::subscribe(MqttTopic {
+            topic: format!("zigbee2mqtt/bridge/{topic}"),
+            server: server.clone(),
+        });
+    }
 
-                // Persist the device
-                device.save(&mut tx).await?;
+    // Groups get their features from their members, so both lists are kept around.
+    // Until the device list arrived a group would look like it lost all its features
+    let mut exposes = None;
+    let mut groups = None;
 
-                for feature in features {
-                    feature.save(&device.id, &mut tx).await?;
-                }
+    while let Some(next) = channel.next().await {
+        let Ok((topic, data)) = next else {
+            // Starting over subscribes again, which gets us the retained lists once more
+            anyhow::bail!("Missed updates of the bridge, starting over");
+        };
 
-                tx.commit().await?;
+        match topic.as_str() {
+            "zigbee2mqtt/bridge/devices" => {
+                let device_spec: Vec<Device> = serde_json::de::from_reader(data.reader())?;
 
-                spawn_device_tasks(&task, &device);
+                exposes = Some(sync_bridge_devices(parent, server, device_spec, task).await?);
+            }
+            "zigbee2mqtt/bridge/groups" => {
+                let group_spec: Vec<Group> = serde_json::de::from_reader(data.reader())?;
 
-                crate::device::notify_changed(device);
+                groups = Some(group_spec);
             }
+            _ => continue,
+        }
+
+        if let (Some(groups), Some(exposes)) = (&groups, &exposes) {
+            sync_bridge_groups(parent, server, groups, exposes, task).await?;
         }
     }
 
     Ok(())
 }
 
-pub async fn zigbee2mqtt_device(device_id: IString, _: Task) -> Result<()> {
+/// Bring the children of the integration in line with the devices the bridge knows about,
+/// returns the features of each device by ieee address
+async fn sync_bridge_devices(
+    parent: &str,
+    server: &MqttServerInfo,
+    device_spec: Vec<Device>,
+    task: &Task,
+) -> Result<HashMap<String, Vec<crate::device::Feature>>> {
+    // Devices without a definition are still on the network, we just can not use them yet
+    let on_network: HashSet<String> = device_spec.iter().map(|d| d.ieee_address.clone()).collect();
+
+    let mut known = known_children(parent).await?;
+    let mut exposes = HashMap::new();
+
+    let iter = device_spec
+        .into_iter()
+        .filter_map(|d| d.into_device(parent, server.clone()));
+
+    for (device, features) in iter {
+        let id = device.id.clone();
+
+        save_bridged(device, &features, &mut known, task).await?;
+        exposes.insert(id, features);
+    }
+
+    for (id, device) in known {
+        // Only devices the bridge told us about can vanish from it
+        let bridged = matches!(device.task_spec, TaskSpec::Zigbee2MqttDevice(_));
+
+        if bridged && !on_network.contains(&id) {
+            debug!("{id} is no longer part of the zigbee network, marking it unavailable");
+            mark_unavailable(&id).await?;
+
+            // Started again once the device is back on the network
+            if let Some(label) = crate::device::device_task_label(&device) {
+                task.stop(&label);
+            }
+        }
+    }
+
+    Ok(exposes)
+}
+
+/// Devices that left the network keep their history and automations, they can come back.
+/// Removing them for good is up to the user
+async fn mark_unavailable(device_id: &str) -> Result<()> {
+    let mut conn = db::connection().await?;
+    let mut features = crate::device::Feature::load_by_device_readable(device_id, &mut conn);
+
+    while let Some(feature) = features.try_next().await? {
+        value::set_stale(
+            ValueId::new(device_id, &feature.id),
+            "Device is no longer part of the zigbee network".into(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Create a device for every group of the bridge and remove the groups that are gone
+async fn sync_bridge_groups(
+    parent: &str,
+    server: &MqttServerInfo,
+    groups: &[Group],
+    exposes: &HashMap<String, Vec<crate::device::Feature>>,
+    task: &Task,
+) -> Result<()> {
+    let mut known = known_children(parent).await?;
+
+    for group in groups {
+        let (device, features) = group.to_device(parent, server.clone(), exposes);
+
+        save_bridged(device, &features, &mut known, task).await?;
+    }
+
+    for (id, device) in known {
+        if matches!(device.task_spec, TaskSpec::Zigbee2MqttGroup(_)) {
+            debug!("Group {id} was removed from zigbee2mqtt, removing it");
+            crate::device::delete_device(task, &id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn known_children(parent: &str) -> Result<HashMap<String, crate::device::Device>> {
+    let mut conn = db::connection().await?;
+
+    let known = crate::device::Device::children(parent, &mut conn)
+        .map_ok(|d| (d.id.clone(), d))
+        .try_collect()
+        .await?;
+
+    Ok(known)
+}
+
+/// Persist a device of the bridge and its features, the device task is only
+/// restarted if something it depends on changed
+async fn save_bridged(
+    device: crate::device::Device,
+    features: &[crate::device::Feature],
+    known: &mut HashMap<String, crate::device::Device>,
+    task: &Task,
+) -> Result<()> {
+    use crate::device::Feature;
+
+    let _busy = task.busy();
+    let mut tx = db::begin().await?;
+
+    let before: Vec<Feature> = Feature::load_by_device(&device.id, &mut tx)
+        .try_filter(|f| future::ready(!f.virt))
+        .try_collect()
+        .await?;
+
+    // Persist the device, a name the user gave it is kept
+    device.save(&mut tx).await?;
+    let device = crate::device::Device::load_by_id(&device.id, &mut tx).await?;
+
+    for feature in features {
+        feature.save(&device.id, &mut tx).await?;
+    }
+
+    // Exposes can disappear with a firmware update or a new converter
+    let ids: Vec<&str> = features.iter().map(|f| f.id.as_str()).collect();
+    let deleted = Feature::delete_missing(&device.id, &ids, &mut tx).await?;
+
+    tx.commit().await?;
+
+    crate::device::forget_features(task, &device.id, &deleted);
+
+    let changed = match known.remove(&device.id) {
+        None => true,
+        Some(old) => {
+            // The topic follows the friendly name, renaming it in zigbee2mqtt moves the device.
+            // New server settings move it as well
+            let moved = match (&old.task_spec, &device.task_spec) {
+                (TaskSpec::Zigbee2MqttDevice(a), TaskSpec::Zigbee2MqttDevice(b))
+                | (TaskSpec::Zigbee2MqttGroup(a), TaskSpec::Zigbee2MqttGroup(b)) => a != b,
+                _ => true,
+            };
+
+            moved
+                || old.name != device.name
+                || feature_signature(&before) != feature_signature(features)
+        }
+    };
+
+    // Devices that were off the network had their task stopped
+    let stopped = crate::device::device_task_label(&device)
+        .map(|label| !task.has_task(&label))
+        .unwrap_or(false);
+
+    if changed || stopped {
+        spawn_device_tasks(task, &device);
+        crate::device::notify_changed(device);
+    }
+
+    Ok(())
+}
+
+/// What a device task cares about of its features
+fn feature_signature(
+    features: &[crate::device::Feature],
+) -> BTreeMap<&str, (ValueKind, ValueDirection, &Json)> {
+    features
+        .iter()
+        .map(|f| (f.id.as_str(), (f.kind, f.direction, &f.meta)))
+        .collect()
+}
+
+pub async fn zigbee2mqtt_device(device_id: IString, task: Task) -> Result<()> {
     // Get the devices and their features from the database
     let (device, features) = {
         use crate::device::{Device, Feature};
 
         let mut conn = db::connection().await?;
         let device = Device::load_by_id(device_id.into(), &mut conn).await?;
-        let features: Vec<Feature> = Feature::load_by_device_readable(&device.id, &mut conn)
+        let features: Vec<Feature> = Feature::load_by_device(&device.id, &mut conn)
+            .try_filter(|f| future::ready(!f.virt))
             .try_collect()
             .await?;
 
         (device, features)
     };
 
-    use TaskSpec::Zigbee2MqttDevice;
-
-    let Zigbee2MqttDevice(subscribe) = device.task_spec else {
+    let (TaskSpec::Zigbee2MqttDevice(subscribe) | TaskSpec::Zigbee2MqttGroup(subscribe)) =
+        device.task_spec
+    else {
         anyhow::bail!("zigbee2mqtt_device did not get a task spec it expected, this is a bug");
     };
 
+    let availability = MqttTopic {
+        topic: format!("{}/availability", subscribe.topic),
+        server: subscribe.server.clone(),
+    };
+
     // Subscribe to all messages we receive from MQTT servers
     let incoming = async {
+        let mut incoming = mqtt::incoming();
+
         // Tell the MQTT worker that we want to subscribe to a server and topic
         mqtt::subscribe(subscribe.clone());
+        mqtt::subscribe(availability.clone());
 
-        let mut incoming = mqtt::incoming();
+        // Not every feature is published on its own, ask for the ones we can
+        read_back(&subscribe, &features);
 
         while let Some((topic, data)) = incoming.next().await {
+            if topic == availability.topic {
+                match is_online(&data) {
+                    Some(true) => read_back(&subscribe, &features),
+                    Some(false) => {
+                        for spec in features.iter().filter(|f| f.direction.can_read()) {
+                            let key = ValueId::new(device_id, &spec.id);
+                            value::set_current(key, Err("Device is offline".into()));
+                        }
+                    }
+                    None => warn!("Unknown availability for {}", device.name),
+                }
+
+                continue;
+            }
+
             if topic != subscribe.topic {
                 continue;
             }
@@ -131,41 +374,10 @@ pub async fn zigbee2mqtt_device(device_id: IString, _: Task) -> Result<()> {
                 }
             };
 
-            for spec in &features {
-                let ptr = &format!("/{}", spec.id);
+            for spec in features.iter().filter(|f| f.direction.can_read()) {
                 let key = ValueId::new(device_id, &spec.id);
 
-                let Some(mut value) = json.pointer(ptr) else {
-                        // Set error in Sensor map
-                        let v = Err(format!("Invalid JSON pointer {}\n{:#?}", ptr, json));
-                        value::set_current(key, v);
-                        continue;
-                    };
-
-                // Rewrite Zigbee2Mqtt Binary to a boolean before we validate
-                // if the base value is not already a boolean
-                if spec.kind == ValueKind::Bool && !value.is_boolean() {
-                    let Some(on) = spec.meta.get("value_on") else {
-                            let v = Err("meta value 'value_on' required for binary device".into());
-                        value::set_current(key, v);
-                            continue;
-                        };
-
-                    let Some(off) = spec.meta.get("value_off") else {
-                            let v = Err("meta value 'value_off' required for binary device".into());
-                        value::set_current(key, v);
-                            continue;
-                        };
-
-                    if value == on {
-                        value = &Json::Bool(true)
-                    } else if value == off {
-                        value = &Json::Bool(false)
-                    }
-                }
-
-                let output = spec.validate(value);
-                value::set_current(key, output);
+                value::set_current(key, spec.extract(&json, &pointer(spec)));
             }
         }
 
@@ -173,44 +385,24 @@ pub async fn zigbee2mqtt_device(device_id: IString, _: Task) -> Result<()> {
     };
 
     let outgoing = async {
-        // Subscribe to value push
-        let mut outgoing =
-            value::push_subscribe().filter(|(id, _)| future::ready(id.device == device_id));
+        // Subscribe to value push, what is queued still goes out when the device is stopped
+        let mut outgoing = task.until_stopped(value::push_subscribe_device(device_id));
 
-        while let Some((id, mut value)) = outgoing.next().await {
+        while let Some((id, value)) = outgoing.next().await {
             let fid: &str = id.feature.into();
 
             let feature = features.iter().find(|f| f.id == fid);
 
             if let Some(spec) = feature {
-                // Rewrite Zigbee2Mqtt Binary to a boolean before we validate
-                // if the base value is not already a boolean
-                if spec.kind == ValueKind::Bool {
-                    let Some(on) = spec.meta.get("value_on") else {
-                            // let v = FeatureValue::Err("meta value 'value_on' required for binary device".into());
-                            continue;
-                        };
-
-                    let Some(off) = spec.meta.get("value_off") else {
-                            // let v = FeatureValue::Err("meta value 'value_off' required for binary device".into());
-                            continue;
-                        };
-
-                    if value == Json::Bool(true) {
-                        value = on.clone();
-                    } else {
-                        value = off.clone();
-                    }
-                }
-
-                let mut o = serde_json::Map::new();
-                o.insert(spec.id.clone(), value);
+                let Some(payload) = spec.to_payload(&pointer(spec), value) else {
+                    // Binary features need value_on and value_off to be written
+                    continue;
+                };
 
-                let payload = serde_json::Value::Object(o);
                 let bytes = serde_json::ser::to_vec(&payload)?;
 
                 let sub = MqttTopic {
-                    topic: format!("zigbee2mqtt/{}/set", device.id),
+                    topic: format!("{}/set", subscribe.topic),
                     server: subscribe.server.clone(),
                 };
 
@@ -227,3 +419,69 @@ pub async fn zigbee2mqtt_device(device_id: IString, _: Task) -> Result<()> {
 
     Ok(())
 }
+
+/// Ask zigbee2mqtt to read the readable features of a device again
+pub async fn refresh(device_id: &str) -> Result<()> {
+    use crate::device::{Device, Feature};
+
+    let mut conn = db::connection().await?;
+    let device = Device::load_by_id(device_id, &mut conn).await?;
+    let features: Vec<Feature> = Feature::load_by_device_readable(&device.id, &mut conn)
+        .try_collect()
+        .await?;
+
+    let (TaskSpec::Zigbee2MqttDevice(subscribe) | TaskSpec::Zigbee2MqttGroup(subscribe)) =
+        device.task_spec
+    else {
+        anyhow::bail!("{device_id} is not a zigbee2mqtt device");
+    };
+
+    read_back(&subscribe, &features);
+
+    Ok(())
+}
+
+/// Send a `/get` for all features with the read access bit, the answer arrives on the normal topic
+fn read_back(subscribe: &MqttTopic, features: &[crate::device::Feature]) {
+    // Properties of a composite are read together with the composite
+    let request: serde_json::Map<String, Json> = features
+        .iter()
+        .filter(|f| f.meta.get("readable") == Some(&Json::Bool(true)))
+        .filter_map(|f| {
+            let pointer = pointer(f);
+            let property = pointer.split('/').nth(1)?.to_string();
+            Some((property, Json::String(String::new())))
+        })
+        .collect();
+
+    if request.is_empty() {
+        return;
+    }
+
+    let get = MqttTopic {
+        topic: format!("{}/get", subscribe.topic),
+        server: subscribe.server.clone(),
+    };
+
+    mqtt::publish(get, Json::Object(request).to_string().into_bytes());
+}
+
+/// Where the value of a feature is in the device payload, nested for properties of composites
+fn pointer(feature: &crate::device::Feature) -> String {
+    match feature.meta.get("pointer").and_then(|p| p.as_str()) {
+        Some(pointer) => pointer.into(),
+        None => format!("/{}", feature.id),
+    }
+}
+
+/// Availability is either `online`/`offline` or `{"state": "online"}` depending on the version
+fn is_online(data: &[u8]) -> Option<bool> {
+    let json: Json = serde_json::from_slice(data)
+        .unwrap_or_else(|_| Json::String(String::from_utf8_lossy(data).into_owned()));
+
+    match json.get("state").unwrap_or(&json).as_str()? {
+        "online" => Some(true),
+        "offline" => Some(false),
+        _ => None,
+    }
+}
@@ -1 +1 @@
-pub mod mqtt;
\ No newline at end of file
+pub mod mqtt;
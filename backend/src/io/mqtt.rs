@@ -1,19 +1,45 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::BufReader,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bytes::Bytes;
+use dashmap::DashMap;
 use futures::{Stream, StreamExt};
-use rumqttc::{AsyncClient, ConnAck, Event, EventLoop, MqttOptions, Packet};
+use once_cell::sync::Lazy;
+use rumqttc::{
+    AsyncClient, ConnectionError, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS,
+    SubscribeFilter, TlsConfiguration, Transport,
+};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
+use rustls_pemfile::Item;
 use serde_derive::{Deserialize, Serialize};
-use tokio::time::timeout;
-use tracing::warn;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
 
-use crate::{task::Task, topic::static_topic};
+use crate::{
+    task::Task,
+    topic::{static_topic, Lagged, Overflow},
+};
 
-static_topic!(CLIENTBUS, ClientAction);
-static_topic!(INCOMING, (String, Bytes));
+// Subscribes, publishes and flushes are never dropped, `manage_connections` does not wait on anything
+static_topic!(CLIENTBUS, ClientAction, 1024, Overflow::Grow);
+// Reading from the servers never waits on a consumer, consumers that fall behind are told so
+static_topic!(INCOMING, (String, Bytes), 4096, Overflow::DropOldest);
+static_topic!(STATUS_CHANGED, (MqttServerInfo, ConnectionStatus));
 
-#[derive(Debug, Clone, Serialize, Deserialize, Eq)]
+static STATUS: Lazy<DashMap<MqttServerInfo, ConnectionStatus>> = Lazy::new(DashMap::default);
+
+/// Servers only compare equal with the same credentials and TLS settings,
+/// there is one connection per host and port and the latest settings win
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub struct MqttServerInfo {
     pub host: String,
     pub port: u16,
@@ -21,6 +47,8 @@ pub struct MqttServerInfo {
     pub username: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<MqttTls>,
 }
 
 impl MqttServerInfo {
@@ -30,30 +58,122 @@ impl MqttServerInfo {
         port: u16,
         username: Option<String>,
         password: Option<String>,
+        tls: Option<MqttTls>,
     ) -> MqttServerInfo {
         MqttServerInfo {
             host,
             port,
             username,
             password,
+            tls,
         }
     }
-}
 
-impl std::cmp::PartialEq for MqttServerInfo {
-    fn eq(&self, other: &Self) -> bool {
+    fn same_address(&self, other: &Self) -> bool {
         self.host == other.host && self.port == other.port
     }
 }
 
-impl std::hash::Hash for MqttServerInfo {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.host.hash(state);
-        self.port.hash(state);
+/// How to set up TLS towards a MQTT server, all paths are PEM files
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub struct MqttTls {
+    /// CA to trust, the system roots are used if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<String>,
+    /// Certificate to authenticate the client with, requires client_key_file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key_file: Option<String>,
+    /// Do not verify the server certificate at all
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+impl MqttTls {
+    fn client_config(&self) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+
+        if let Some(ca_file) = &self.ca_file {
+            let mut reader = BufReader::new(open(ca_file)?);
+
+            for cert in rustls_pemfile::certs(&mut reader)? {
+                roots.add(&Certificate(cert))?;
+            }
+        } else {
+            for cert in rustls_native_certs::load_native_certs()? {
+                // Ignore roots rustls can not parse like rumqttc does
+                let _ = roots.add(&Certificate(cert.0));
+            }
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+
+        let mut config = match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let mut reader = BufReader::new(open(cert_file)?);
+                let certs = rustls_pemfile::certs(&mut reader)?
+                    .into_iter()
+                    .map(Certificate)
+                    .collect();
+
+                let mut reader = BufReader::new(open(key_file)?);
+                let key = rustls_pemfile::read_all(&mut reader)?
+                    .into_iter()
+                    .find_map(|item| match item {
+                        Item::RSAKey(k) | Item::PKCS8Key(k) | Item::ECKey(k) => Some(k),
+                        _ => None,
+                    });
+
+                let Some(key) = key else {
+                    anyhow::bail!("No private key found in {key_file}");
+                };
+
+                builder.with_single_cert(certs, PrivateKey(key))?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => anyhow::bail!("Client certificates need both a cert and a key file"),
+        };
+
+        if self.insecure {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification));
+        }
+
+        Ok(config)
+    }
+}
+
+fn open(path: &str) -> Result<File> {
+    File::open(path).with_context(|| format!("Could not open {path}"))
+}
+
+/// Accepts any server certificate, for the insecure TLS option
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
     }
 }
 
 const MAX_PACKET_SIZE: usize = 2 * 1024 * 1024;
+/// How many requests can be queued towards a server before we start dropping them
+const REQUEST_CAPACITY: usize = 100;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub struct MqttTopic {
@@ -64,7 +184,12 @@ pub struct MqttTopic {
 #[derive(Clone)]
 enum ClientAction {
     S(MqttTopic),
-    P(MqttTopic, Vec<u8>),
+    /// Publish, retained if the flag is set
+    P(MqttTopic, Vec<u8>, bool),
+    /// Last will of a server, sent retained by the broker when we vanish and by us when flushing
+    W(MqttTopic, Vec<u8>),
+    /// Send everything that was published so far and disconnect
+    Flush(flume::Sender<()>),
 }
 
 pub fn subscribe(topic: MqttTopic) {
@@ -72,18 +197,143 @@ pub fn subscribe(topic: MqttTopic) {
 }
 
 pub fn publish(topic: MqttTopic, value: Vec<u8>) {
-    CLIENTBUS.publish(ClientAction::P(topic, value));
+    CLIENTBUS.publish(ClientAction::P(topic, value, false));
 }
 
+/// Publish a message the broker keeps for subscribers that come later
+pub fn publish_retained(topic: MqttTopic, value: Vec<u8>) {
+    CLIENTBUS.publish(ClientAction::P(topic, value, true));
+}
+
+/// Set the last will of a server, a connection that is already up reconnects to register it
+pub fn last_will(topic: MqttTopic, value: Vec<u8>) {
+    CLIENTBUS.publish(ClientAction::W(topic, value));
+}
+
+/// Send out pending publishes and disconnect from every server, this also ends `manage_connections`
+pub async fn flush(timeout: Duration) {
+    let (tx, rx) = flume::bounded(1);
+
+    CLIENTBUS.publish(ClientAction::Flush(tx));
+
+    // Nobody there to flush is fine too, the sender is dropped right away
+    if tokio::time::timeout(timeout, rx.recv_async())
+        .await
+        .is_err()
+    {
+        warn!("Could not flush mqtt connections in {timeout:?}");
+    }
+}
+
+/// Messages from all servers, messages missed because the consumer fell behind are skipped
 pub fn incoming() -> impl Stream<Item = (String, Bytes)> {
     INCOMING.subscribe()
 }
 
+/// Messages from all servers, with an error in place of the ones that were missed
+///
+/// Subscribing to a topic again makes the server send its retained messages once more
+pub fn incoming_lagged() -> impl Stream<Item = Result<(String, Bytes), Lagged>> {
+    INCOMING.subscribe_lagged()
+}
+
+/// Where a connection to a MQTT server is in its life cycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    BackingOff,
+}
+
+impl ConnectionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::BackingOff => "backing_off",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    /// The error that made us lose the connection last time
+    pub last_error: Option<String>,
+}
+
+/// The current status of the connection to a server, None if we never tried to connect
+pub fn connection_status(server: &MqttServerInfo) -> Option<ConnectionStatus> {
+    STATUS.get(server).map(|s| s.clone())
+}
+
+/// Listen for changes to the connection status of all servers
+pub fn connection_changes() -> impl Stream<Item = (MqttServerInfo, ConnectionStatus)> {
+    STATUS_CHANGED.subscribe()
+}
+
+fn set_status(server: &MqttServerInfo, state: ConnectionState, error: Option<String>) {
+    let status = {
+        let mut status = STATUS.entry(server.clone()).or_insert(ConnectionStatus {
+            state,
+            last_error: None,
+        });
+
+        status.state = state;
+
+        if error.is_some() {
+            status.last_error = error;
+        }
+
+        status.clone()
+    };
+
+    STATUS_CHANGED.publish((server.clone(), status));
+}
+
+/// Topics a connection should be subscribed to, kept so we can subscribe again after a reconnect
+#[derive(Default)]
+struct Subscriptions {
+    connected: bool,
+    /// The connection has been replaced and should stop
+    closed: bool,
+    topics: BTreeSet<String>,
+}
+
+struct Connection {
+    client: AsyncClient,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    driver: Option<JoinHandle<()>>,
+}
+
+impl Connection {
+    /// Topics to subscribe to again when the connection is replaced
+    fn topics(&self) -> BTreeSet<String> {
+        let subscriptions = self.subscriptions.lock().expect("Lock mqtt subscriptions");
+        subscriptions.topics.clone()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut subscriptions = self.subscriptions.lock().expect("Lock mqtt subscriptions");
+        subscriptions.closed = true;
+
+        // The disconnect is queued behind what was published before,
+        // if it can not be queued there is nothing to wait for
+        if self.client.try_disconnect().is_err() {
+            subscriptions.connected = false;
+        }
+    }
+}
+
 /**
- * A task to manage connection going out to many different MQTT server, we connect and subscribe to the first topic at the same time
+ * A task to manage connection going out to many different MQTT server, we connect when the first topic is subscribed to.
+ * Connections are kept alive in the background and reconnect with an exponential backoff
  */
 pub async fn manage_connections(_: Task) -> Result<()> {
-    let mut connections: HashMap<MqttServerInfo, AsyncClient> = HashMap::new();
+    let mut connections: HashMap<MqttServerInfo, Connection> = HashMap::new();
+    let mut wills: HashMap<MqttServerInfo, (String, Vec<u8>)> = HashMap::new();
 
     let mut actions = CLIENTBUS.subscribe();
 
@@ -92,39 +342,78 @@ pub async fn manage_connections(_: Task) -> Result<()> {
 
         match r {
             S(sub) => {
-                let key = sub.server.clone();
-
-                if let Some(client) = connections.get_mut(&key) {
-                    client
-                        .subscribe(&sub.topic, rumqttc::QoS::AtLeastOnce)
-                        .await?;
-                } else {
-                    let (client, mut eventloop) = connect(&sub.server).await?;
-
-                    tokio::spawn(async move {
-                        while let Ok(notification) = eventloop.poll().await {
-                            if let Event::Incoming(Packet::Publish(p)) = notification {
-                                INCOMING.publish((p.topic, p.payload));
-                            }
-                        }
+                let connection = connection(&mut connections, &wills, &sub.server);
 
-                        println!("mqtt background died");
-                    });
+                let mut subscriptions = connection
+                    .subscriptions
+                    .lock()
+                    .expect("Lock mqtt subscriptions");
 
-                    client
-                        .subscribe(&sub.topic, rumqttc::QoS::AtLeastOnce)
-                        .await?;
+                subscriptions.topics.insert(sub.topic.clone());
 
-                    connections.insert(key, client);
+                // If we are not connected yet the topic will be subscribed to once we are.
+                // Subscribing again is on purpose, the server sends retained messages
+                // again for whoever started over
+                if subscriptions.connected {
+                    if let Err(e) = connection
+                        .client
+                        .try_subscribe(&sub.topic, QoS::AtLeastOnce)
+                    {
+                        warn!("Could not subscribe to {}, {e:?}", sub.topic);
+                    }
+                }
+            }
+            W(will, bytes) => {
+                let will_changed =
+                    wills.get(&will.server) != Some(&(will.topic.clone(), bytes.clone()));
+                wills.insert(will.server.clone(), (will.topic, bytes));
+
+                // The will is part of connecting, so an existing connection has to start over
+                if will_changed {
+                    if let Some(old) = connections.remove(&will.server) {
+                        let connection =
+                            connect(&will.server, old.topics(), wills.get(&will.server));
+                        connections.insert(will.server, connection);
+                    }
+                }
+            }
+            Flush(done) => {
+                // A clean disconnect does not trigger the will, so we send it ourselves
+                for (server, connection) in &connections {
+                    if let Some((topic, bytes)) = wills.get(server) {
+                        if let Err(e) = connection.client.try_publish(
+                            topic,
+                            QoS::AtLeastOnce,
+                            true,
+                            bytes.clone(),
+                        ) {
+                            warn!("Could not publish last will to {topic}, {e:?}");
+                        }
+                    }
                 }
+
+                let drivers: Vec<_> = connections
+                    .drain()
+                    .filter_map(|(_, mut connection)| connection.driver.take())
+                    .collect();
+
+                futures::future::join_all(drivers).await;
+
+                let _ = done.send(());
+
+                return Ok(());
             }
-            P(server, bytes) => {
-                if let Some(client) = connections.get(&server.server) {
-                    client
-                        .publish(&server.topic, rumqttc::QoS::AtLeastOnce, false, bytes)
-                        .await?;
-                } else {
-                    warn!("Tried to push to mqtt server we are not connected to, fix this");
+            P(server, bytes, retain) => {
+                // Devices that only write never subscribe, publishing connects as well.
+                // Until we are connected the publish waits in the client
+                let connection = connection(&mut connections, &wills, &server.server);
+
+                if let Err(e) =
+                    connection
+                        .client
+                        .try_publish(&server.topic, QoS::AtLeastOnce, retain, bytes)
+                {
+                    warn!("Could not publish to {}, {e:?}", server.topic);
                 }
             }
         }
@@ -133,7 +422,46 @@ pub async fn manage_connections(_: Task) -> Result<()> {
     Ok(())
 }
 
-async fn connect(server_info: &MqttServerInfo) -> Result<(AsyncClient, EventLoop)> {
+/// The connection to a server, connecting if we have not before
+fn connection<'a>(
+    connections: &'a mut HashMap<MqttServerInfo, Connection>,
+    wills: &HashMap<MqttServerInfo, (String, Vec<u8>)>,
+    server: &MqttServerInfo,
+) -> &'a Connection {
+    if !connections.contains_key(server) {
+        // Credentials or TLS changed, or we have not connected before.
+        // Topics we already subscribed to carry over to the new connection
+        let previous = connections.keys().find(|s| s.same_address(server)).cloned();
+
+        let topics = match previous {
+            Some(previous) => {
+                warn!(
+                    "Settings for mqtt server {}:{} changed, reconnecting",
+                    previous.host, previous.port
+                );
+
+                STATUS.remove(&previous);
+
+                connections
+                    .remove(&previous)
+                    .expect("Previous connection exists")
+                    .topics()
+            }
+            None => BTreeSet::new(),
+        };
+
+        let connection = connect(server, topics, wills.get(server));
+        connections.insert(server.clone(), connection);
+    }
+
+    connections.get(server).expect("Connected above")
+}
+
+fn connect(
+    server_info: &MqttServerInfo,
+    topics: BTreeSet<String>,
+    will: Option<&(String, Vec<u8>)>,
+) -> Connection {
     let [a, b]: [u64; 2] = rand::random();
     let client_id = format!("bramble-{}-{}{}", env!("CARGO_PKG_VERSION"), a, b);
 
@@ -141,18 +469,238 @@ async fn connect(server_info: &MqttServerInfo) -> Result<(AsyncClient, EventLoop
     mqttoptions.set_keep_alive(Duration::from_secs(30));
     mqttoptions.set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
 
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    if let Some(username) = &server_info.username {
+        let password = server_info.password.clone().unwrap_or_default();
+        mqttoptions.set_credentials(username, password);
+    }
+
+    if let Some((topic, bytes)) = will {
+        mqttoptions.set_last_will(LastWill::new(topic, bytes.clone(), QoS::AtLeastOnce, true));
+    }
+
+    let (client, eventloop) = AsyncClient::new(mqttoptions, REQUEST_CAPACITY);
+    let subscriptions = Arc::new(Mutex::new(Subscriptions {
+        topics,
+        ..Default::default()
+    }));
+
+    let driver = tokio::spawn(drive(
+        server_info.clone(),
+        eventloop,
+        client.clone(),
+        subscriptions.clone(),
+    ));
+
+    Connection {
+        client,
+        subscriptions,
+        driver: Some(driver),
+    }
+}
+
+/// Poll the connection for as long as the client is around, polling after an error reconnects
+async fn drive(
+    server: MqttServerInfo,
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    // TLS is set up in here so broken certificate files are retried like any other connection error
+    let mut tls_ready = server.tls.is_none();
+
+    set_status(&server, ConnectionState::Connecting, None);
+
+    loop {
+        if !tls_ready {
+            if subscriptions
+                .lock()
+                .expect("Lock mqtt subscriptions")
+                .closed
+            {
+                break;
+            }
+
+            let tls = server.tls.as_ref().expect("TLS settings");
+
+            match tls.client_config() {
+                Ok(config) => {
+                    let transport = Transport::Tls(TlsConfiguration::Rustls(Arc::new(config)));
+                    eventloop.mqtt_options.set_transport(transport);
+                    tls_ready = true;
+                }
+                Err(e) => {
+                    error!(
+                        "Could not setup TLS for {}:{}, retry in {backoff:?}\n{e:?}",
+                        server.host, server.port
+                    );
+
+                    back_off(&server, &mut backoff, format!("{e:#}")).await;
+                    continue;
+                }
+            }
+        }
+
+        let event = eventloop.poll().await;
+
+        let (closed, connected) = {
+            let subscriptions = subscriptions.lock().expect("Lock mqtt subscriptions");
+            (subscriptions.closed, subscriptions.connected)
+        };
+
+        if closed {
+            // Keep going until what was queued before the disconnect went out
+            let done =
+                !connected || matches!(event, Err(_) | Ok(Event::Outgoing(Outgoing::Disconnect)));
+
+            if done {
+                debug!("mqtt connection to {}:{} closed", server.host, server.port);
+                break;
+            }
+
+            continue;
+        }
+
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                debug!("mqtt connected to {}:{}", server.host, server.port);
+
+                backoff = MIN_BACKOFF;
+
+                let mut subscriptions = subscriptions.lock().expect("Lock mqtt subscriptions");
+                subscriptions.connected = true;
+
+                if !subscriptions.topics.is_empty() {
+                    let filters = subscriptions
+                        .topics
+                        .iter()
+                        .map(|t| SubscribeFilter::new(t.clone(), QoS::AtLeastOnce));
+
+                    if let Err(e) = client.try_subscribe_many(filters) {
+                        error!("Could not subscribe after connecting, {e:?}");
+                    }
+                }
+
+                drop(subscriptions);
+
+                set_status(&server, ConnectionState::Connected, None);
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) => {
+                INCOMING.publish((p.topic, p.payload));
+            }
+            Ok(_) => {}
+            Err(ConnectionError::RequestsDone) => {
+                // All clients are gone, nothing more to do
+                break;
+            }
+            Err(e) => {
+                subscriptions
+                    .lock()
+                    .expect("Lock mqtt subscriptions")
+                    .connected = false;
 
-    // Look for ConnAck, but only for so long
-    timeout(Duration::from_secs(60), async {
-        loop {
-            // Make sure we are connected before we start the actor
-            if let Event::Incoming(Packet::ConnAck(ConnAck { .. })) = eventloop.poll().await? {
-                return anyhow::Ok(());
+                warn!(
+                    "mqtt connection to {}:{} failed, retry in {backoff:?}\n{e:?}",
+                    server.host, server.port
+                );
+
+                back_off(&server, &mut backoff, e.to_string()).await;
             }
         }
-    })
-    .await??;
+    }
+}
+
+/// Wait before the next attempt to connect, each attempt waits twice as long up to `MAX_BACKOFF`
+async fn back_off(server: &MqttServerInfo, backoff: &mut Duration, error: String) {
+    set_status(server, ConnectionState::BackingOff, Some(error));
+
+    tokio::time::sleep(*backoff).await;
+    *backoff = (*backoff * 2).min(MAX_BACKOFF);
+
+    set_status(server, ConnectionState::Connecting, None);
+}
 
-    Ok((client, eventloop))
+#[cfg(test)]
+mod test {
+    use super::{MqttServerInfo, MqttTls};
+
+    fn testdata(file: &str) -> Option<String> {
+        Some(format!(
+            "{}/testdata/mqtt/{file}",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+    }
+
+    #[test]
+    fn client_config_with_ca_and_client_cert() {
+        let tls = MqttTls {
+            ca_file: testdata("ca.pem"),
+            client_cert_file: testdata("client.pem"),
+            client_key_file: testdata("client.key"),
+            insecure: false,
+        };
+
+        assert!(tls
+            .client_config()
+            .unwrap()
+            .client_auth_cert_resolver
+            .has_certs());
+
+        let insecure = MqttTls {
+            ca_file: testdata("ca.pem"),
+            insecure: true,
+            ..Default::default()
+        };
+
+        assert!(!insecure
+            .client_config()
+            .unwrap()
+            .client_auth_cert_resolver
+            .has_certs());
+    }
+
+    #[test]
+    fn client_config_errors() {
+        let missing_ca = MqttTls {
+            ca_file: testdata("missing.pem"),
+            ..Default::default()
+        };
+
+        let error = format!("{:#}", missing_ca.client_config().unwrap_err());
+        assert!(error.starts_with("Could not open"), "{error}");
+
+        let missing_key = MqttTls {
+            ca_file: testdata("ca.pem"),
+            client_cert_file: testdata("client.pem"),
+            ..Default::default()
+        };
+
+        let error = missing_key.client_config().unwrap_err().to_string();
+        assert_eq!(error, "Client certificates need both a cert and a key file");
+
+        let not_a_key = MqttTls {
+            ca_file: testdata("ca.pem"),
+            client_cert_file: testdata("client.pem"),
+            client_key_file: testdata("client.pem"),
+            insecure: false,
+        };
+
+        let error = not_a_key.client_config().unwrap_err().to_string();
+        assert!(error.starts_with("No private key found in"), "{error}");
+    }
+
+    #[test]
+    fn settings_are_part_of_the_server() {
+        let plain = MqttServerInfo::new("broker".into(), 1883, None, None, None);
+
+        let mut login = plain.clone();
+        login.username = Some("bramble".into());
+
+        let mut tls = plain.clone();
+        tls.tls = Some(MqttTls::default());
+
+        assert_ne!(plain, login);
+        assert_ne!(plain, tls);
+        assert!(plain.same_address(&login) && plain.same_address(&tls));
+    }
 }
@@ -1,6 +1,7 @@
 mod api;
 mod db;
 mod device;
+mod history;
 mod http;
 mod integration;
 mod io;
@@ -10,20 +11,75 @@ mod task;
 mod topic;
 mod value;
 
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use anyhow::Result;
+use tracing::{info, warn};
 
-use task::Task;
+use task::{Task, TaskState};
+
+/// How long tasks get to finish what they are doing when shutting down
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    let mut connection = db::connection().await?;
-    sqlx::migrate!().run(&mut connection).await?;
+    {
+        let mut connection = db::connection().await?;
+        sqlx::migrate!().run(&mut connection).await?;
+    }
+
+    let mut group = task::create_group(init);
+
+    let completed = tokio::select! {
+        result = group.complete() => result,
+        result = shutdown_signal() => result,
+    };
+
+    info!("Shutting down");
+
+    for status in group.status() {
+        if status.state == TaskState::Failed {
+            let error = status.last_error.unwrap_or_default();
+            warn!("task {} is down with error {error}", status.label);
+        }
+    }
+
+    // MQTT goes last so the publishes of the other tasks make it out,
+    // device tasks send what is queued for them before they stop
+    group.shutdown(&["mqtt_connections"], GRACE_PERIOD).await;
+    io::mqtt::flush(GRACE_PERIOD).await;
+    group.shutdown(&[], GRACE_PERIOD).await;
+
+    db::close().await;
+
+    completed?;
+
+    anyhow::ensure!(group.failed() == 0, "{} tasks failed", group.failed());
+
+    Ok(())
+}
+
+/// Wait for SIGTERM or SIGINT
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = terminate.recv() => {},
+        result = tokio::signal::ctrl_c() => result?,
+    }
+
+    Ok(())
+}
 
-    task::create_group(init).complete().await?;
+/// Wait for Ctrl-C
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+    tokio::signal::ctrl_c().await?;
 
     Ok(())
 }
@@ -33,6 +89,9 @@ async fn init(task: Task) -> Result<()> {
     task.spawn("mqtt_connections", io::mqtt::manage_connections);
     task.spawn("device_restore", device::restore_task);
     task.spawn("catch_virtual", value::catch_virtual_push);
+    task.spawn("history", history::history_task);
+    task.spawn("persist_values", device::persist_values_task);
+    task.spawn("staleness", device::staleness_task);
 
     Ok(())
 }
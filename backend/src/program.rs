@@ -1,7 +1,11 @@
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::Instant,
+};
 
 use anyhow::{ensure, Result};
 use serde_json::Value as Json;
+use time::OffsetDateTime;
 
 use crate::{strings::IString, value::ValueId};
 
@@ -9,8 +13,14 @@ pub trait ProgramNode: Send {
     fn run(&mut self, inputs: &Inputs, outputs: &mut Outputs) -> Result<()>;
 }
 
+type NodeEntry = (u32, Box<dyn ProgramNode>);
+type SlotConnection = ((u32, IString), (u32, IString));
+
 pub struct Inputs<'a> {
+    now: Instant,
+    utc: OffsetDateTime,
     program: &'a BTreeMap<ValueId, Json>,
+    updated: &'a BTreeMap<ValueId, OffsetDateTime>,
     slots: &'a BTreeMap<IString, Vec<(u32, IString)>>,
 
     values: &'a BTreeMap<(u32, IString), Json>,
@@ -38,7 +48,7 @@ impl Inputs<'_> {
         Ok(self.slot(name)?.next())
     }
 
-    pub fn slot_or<'a, T>(&'a self, name: T, value: &'a Json) -> &Json
+    pub fn slot_or<'a, T>(&'a self, name: T, value: &'a Json) -> &'a Json
     where
         T: Into<IString>,
     {
@@ -50,12 +60,28 @@ impl Inputs<'_> {
     }
 
     pub fn program(&self, id: &ValueId) -> Result<&Json> {
-        let Some(json) = self.program.get(&id) else {
+        let Some(json) = self.program.get(id) else {
             anyhow::bail!("No such program input {:?}", id);
         };
 
         Ok(json)
     }
+
+    /// When the program input was last reported without an error,
+    /// as given to [`Program::set_updated`]
+    pub fn updated(&self, id: &ValueId) -> Option<OffsetDateTime> {
+        self.updated.get(id).copied()
+    }
+
+    /// The point in time the program is executed at, nodes should use this instead of the clock
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// The wall clock time the program is executed at, nodes should use this instead of the clock
+    pub fn now_utc(&self) -> OffsetDateTime {
+        self.utc
+    }
 }
 
 pub struct Outputs<'a> {
@@ -64,6 +90,7 @@ pub struct Outputs<'a> {
     slots: &'a BTreeSet<IString>,
 
     values: &'a mut BTreeMap<(u32, IString), Json>,
+    wake: &'a mut Option<Instant>,
 }
 
 impl<'a> Outputs<'a> {
@@ -81,6 +108,14 @@ impl<'a> Outputs<'a> {
     pub fn program(&mut self, id: ValueId, value: Json) {
         self.program.insert(id, value);
     }
+
+    /// Ask for the program to be executed again at a point in time even if no input changes
+    pub fn wake_at(&mut self, at: Instant) {
+        match self.wake {
+            Some(current) if *current <= at => {}
+            _ => *self.wake = Some(at),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -93,13 +128,16 @@ struct Slots {
 pub struct Program {
     /// All the steps of the program in topological order
     steps: Vec<(u32, Slots, Box<dyn ProgramNode>)>,
+    /// The earliest wake up any node asked for during the last execution
+    wake: Option<Instant>,
+    /// Outputs of the last execution
+    last: BTreeMap<ValueId, Json>,
+    /// When program inputs were last reported without an error
+    updated: BTreeMap<ValueId, OffsetDateTime>,
 }
 
 impl Program {
-    pub fn new(
-        nodes: Vec<(u32, Box<dyn ProgramNode>)>,
-        connections: Vec<((u32, IString), (u32, IString))>,
-    ) -> Result<Program> {
+    pub fn new(nodes: Vec<NodeEntry>, connections: Vec<SlotConnection>) -> Result<Program> {
         // Make sure we execute the nodes in the right order
         let nodes = topological_sort(nodes, &connections)?;
 
@@ -135,7 +173,12 @@ impl Program {
             })
             .collect();
 
-        Ok(Program { steps })
+        Ok(Program {
+            steps,
+            wake: None,
+            last: BTreeMap::new(),
+            updated: BTreeMap::new(),
+        })
     }
 
     /// The number of steps to evaluate the program
@@ -143,18 +186,54 @@ impl Program {
         self.steps.len()
     }
 
+    /// When the program wants to be executed again without any input changing
+    pub fn wake(&self) -> Option<Instant> {
+        self.wake
+    }
+
+    /// Track when an input was last reported, None if it has not been or is an error
+    pub fn set_updated(&mut self, id: ValueId, at: Option<OffsetDateTime>) {
+        match at {
+            Some(at) => self.updated.insert(id, at),
+            None => self.updated.remove(&id),
+        };
+    }
+
     pub fn execute(
         &mut self,
         program_input: &BTreeMap<ValueId, Json>,
+    ) -> Result<BTreeMap<ValueId, Json>> {
+        self.execute_at(program_input, Instant::now())
+    }
+
+    /// Execute the program as if the current time is `now`
+    pub fn execute_at(
+        &mut self,
+        program_input: &BTreeMap<ValueId, Json>,
+        now: Instant,
+    ) -> Result<BTreeMap<ValueId, Json>> {
+        self.execute_at_utc(program_input, now, wall_clock(now))
+    }
+
+    /// Execute the program as if the current time is `now` and the wall clock reads `utc`
+    pub fn execute_at_utc(
+        &mut self,
+        program_input: &BTreeMap<ValueId, Json>,
+        now: Instant,
+        utc: OffsetDateTime,
     ) -> Result<BTreeMap<ValueId, Json>> {
         let mut program_output = BTreeMap::new();
+        let mut wake = None;
 
         let mut slot_inputs = BTreeMap::new();
         let mut slot_outputs = BTreeMap::new();
 
         for (id, slots, node) in self.steps.iter_mut() {
             let inputs = Inputs {
+                now,
+                utc,
                 program: program_input,
+                updated: &self.updated,
                 slots: &slots.inputs,
                 values: &slot_inputs,
             };
@@ -164,6 +243,7 @@ impl Program {
                 program: &mut program_output,
                 slots: &slots.outputs,
                 values: &mut slot_outputs,
+                wake: &mut wake,
             };
 
             node.run(&inputs, &mut outputs)?;
@@ -172,14 +252,49 @@ impl Program {
             slot_inputs.append(&mut slot_outputs);
         }
 
+        self.wake = wake;
+        self.last = program_output.clone();
+
         Ok(program_output)
     }
+
+    /// Execute the program and only return the outputs that differ from the last execution
+    pub fn execute_changed(
+        &mut self,
+        program_input: &BTreeMap<ValueId, Json>,
+    ) -> Result<BTreeMap<ValueId, Json>> {
+        self.execute_changed_at(program_input, Instant::now())
+    }
+
+    /// Like [`Program::execute_changed`] as if the current time is `now`
+    pub fn execute_changed_at(
+        &mut self,
+        program_input: &BTreeMap<ValueId, Json>,
+        now: Instant,
+    ) -> Result<BTreeMap<ValueId, Json>> {
+        let previous = std::mem::take(&mut self.last);
+
+        let mut program_output = self.execute_at(program_input, now)?;
+        program_output.retain(|id, value| previous.get(id) != Some(value));
+
+        Ok(program_output)
+    }
+}
+
+/// What the wall clock reads at `at`, which can be in the past or the future
+fn wall_clock(at: Instant) -> OffsetDateTime {
+    let (utc, instant) = (OffsetDateTime::now_utc(), Instant::now());
+
+    match at.checked_duration_since(instant) {
+        Some(ahead) => utc + ahead,
+        None => utc - instant.duration_since(at),
+    }
 }
 
 fn topological_sort(
-    nodes: Vec<(u32, Box<dyn ProgramNode>)>,
-    connections: &[((u32, IString), (u32, IString))],
-) -> Result<Vec<(u32, Box<dyn ProgramNode>)>> {
+    nodes: Vec<NodeEntry>,
+    connections: &[SlotConnection],
+) -> Result<Vec<NodeEntry>> {
     let mut incoming: HashMap<u32, BTreeSet<u32>> = HashMap::new();
     let mut outgoing: HashMap<u32, BTreeSet<u32>> = HashMap::new();
 
@@ -192,7 +307,7 @@ fn topological_sort(
         .iter()
         .map(|(id, _)| *id)
         // Only nodes with no incoming connections to start with.
-        .filter(|id| !incoming.contains_key(&id))
+        .filter(|id| !incoming.contains_key(id))
         .collect();
 
     ensure!(!start.is_empty(), "Progam is not acyclic");
@@ -1,23 +1,179 @@
+use std::collections::VecDeque;
 use std::future::Future;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use async_graphql::{Enum, SimpleObject};
+use dashmap::DashMap;
 use flume::{Receiver, Sender};
 use futures::{
-    stream::{FuturesUnordered, StreamExt},
-    FutureExt,
+    stream::{self, BoxStream, FuturesUnordered, StreamExt},
+    FutureExt, Stream,
+};
+
+use time::OffsetDateTime;
+use tokio::{
+    sync::{oneshot, Notify},
+    task::JoinHandle,
 };
+use tracing::{debug, error, warn};
 
-use tokio::{sync::oneshot, task::JoinHandle};
-use tracing::{debug, error};
+use crate::topic::{static_topic, Overflow};
 
 type TaskFn<F> = fn(task: Task) -> F;
 type TaskFnArg<A, F> = fn(argument: A, task: Task) -> F;
 
+static_topic!(STATUS, TaskStatus, 256, Overflow::DropOldest);
+
+/// Every start of a task gets its own generation, so a task that finishes
+/// can tell whether its label has been taken over by a newer one
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// How long a task that is stopped or replaced gets to finish what it is busy with
+const STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// Stopping a task sends it the grace period it gets to wrap up
+type Running = DashMap<String, (u64, oneshot::Sender<Duration>)>;
+
 #[derive(Clone)]
 pub struct Task {
-    running: Arc<dashmap::DashMap<String, oneshot::Sender<()>>>,
+    running: Arc<Running>,
+    status: Arc<DashMap<String, TaskStatus>>,
     tx: Sender<TaskHandle>,
+    /// Belongs to the task this handle was given to
+    lifecycle: Arc<Lifecycle>,
+}
+
+/// Whether a task was asked to stop and how much work it still has in flight
+#[derive(Default)]
+struct Lifecycle {
+    stopping: AtomicBool,
+    busy: AtomicUsize,
+    changed: Notify,
+}
+
+impl Lifecycle {
+    fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.changed.notify_waiters();
+    }
+
+    async fn stopping(&self) {
+        loop {
+            // Listen before checking so we can not miss the change
+            let changed = self.changed.notified();
+
+            if self.stopping.load(Ordering::SeqCst) {
+                return;
+            }
+
+            changed.await;
+        }
+    }
+
+    async fn idle(&self) {
+        loop {
+            let changed = self.changed.notified();
+
+            if self.busy.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+
+            changed.await;
+        }
+    }
+}
+
+/// Keeps a stopped task from being dropped for as long as it is held, see [`Task::busy`]
+pub struct Busy(Arc<Lifecycle>);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        if self.0.busy.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.changed.notify_waiters();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum TaskState {
+    Running,
+    /// Returned or was stopped
+    Exited,
+    /// Returned an error, a supervised task might be waiting to restart
+    Failed,
+}
+
+/// What is known about a task that has been spawned, finished tasks are kept until
+/// a task with the same label is spawned again
+#[derive(Debug, Clone, SimpleObject)]
+pub struct TaskStatus {
+    pub label: String,
+    pub state: TaskState,
+    /// When the task was spawned, restarts do not change this
+    pub started: OffsetDateTime,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+/// When a supervised task should be started again after it returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    Never,
+    /// Only restart when the task returned an error
+    OnFailure,
+    Always,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    pub restart: Restart,
+    /// Wait before the first restart, doubled on every restart after that
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up when the task had to be restarted this many times within `window`
+    pub max_restarts: usize,
+    /// A task that ran longer than this starts over with the initial backoff
+    pub window: Duration,
+}
+
+impl RestartPolicy {
+    pub const NEVER: RestartPolicy = RestartPolicy {
+        restart: Restart::Never,
+        backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+        max_restarts: 0,
+        window: Duration::ZERO,
+    };
+
+    pub const fn on_failure() -> RestartPolicy {
+        RestartPolicy {
+            restart: Restart::OnFailure,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+            max_restarts: 10,
+            window: Duration::from_secs(10 * 60),
+        }
+    }
+
+    pub const fn always() -> RestartPolicy {
+        RestartPolicy {
+            restart: Restart::Always,
+            ..RestartPolicy::on_failure()
+        }
+    }
+
+    fn should_restart(&self, result: &Result<()>) -> bool {
+        match self.restart {
+            Restart::Never => false,
+            Restart::OnFailure => result.is_err(),
+            Restart::Always => true,
+        }
+    }
 }
 
 impl Task {
@@ -26,59 +182,162 @@ impl Task {
     where
         F: Future<Output = Result<()>> + Send + 'static,
         L: Into<String>,
+    {
+        self.start(label.into(), callback);
+    }
+
+    /// Spawn a named task, if there is a task already running with the same name it will be killed beforehand
+    /// Only difference to spawn is the ability to pass some data
+    pub fn spawn_with_argument<L, A, F>(&self, label: L, argument: A, callback: TaskFnArg<A, F>)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+        L: Into<String>,
+        A: Clone + Send + 'static,
+    {
+        self.spawn_supervised(label, argument, RestartPolicy::NEVER, callback);
+    }
+
+    /// Like [`Task::spawn_with_argument`] but the task is started again with a copy of
+    /// the argument when it returns, as long as the policy allows it
+    pub fn spawn_supervised<L, A, F>(
+        &self,
+        label: L,
+        argument: A,
+        policy: RestartPolicy,
+        callback: TaskFnArg<A, F>,
+    ) where
+        F: Future<Output = Result<()>> + Send + 'static,
+        L: Into<String>,
+        A: Clone + Send + 'static,
     {
         let label = label.into();
-        let (tx, rx) = oneshot::channel();
+        let name = label.clone();
 
-        if let Some(old) = self.running.insert(label.clone(), tx) {
-            old.send(()).expect("Close previous task");
-        }
+        let supervised = move |task: Task| async move {
+            let mut restarts: VecDeque<Instant> = VecDeque::new();
+            let mut backoff = policy.backoff;
 
-        let task = self.clone();
-        let clean_up = label.clone();
-        let running = task.running.clone();
+            loop {
+                let started = Instant::now();
+                let result = callback(argument.clone(), task.clone()).await;
 
-        let handle = tokio::spawn(async move {
-            tokio::select! {
-                _ = rx => Ok(()),
-                result = callback(task) => {
-                    running.remove(&clean_up);
-                    result
+                if !policy.should_restart(&result) || task.is_stopping() {
+                    return result;
+                }
+
+                let now = Instant::now();
+
+                if now - started > policy.window {
+                    backoff = policy.backoff;
+                }
+
+                while let Some(&at) = restarts.front() {
+                    if now - at <= policy.window {
+                        break;
+                    }
+
+                    restarts.pop_front();
+                }
+
+                if restarts.len() >= policy.max_restarts {
+                    return result
+                        .and_then(|_| anyhow::bail!("task kept exiting"))
+                        .with_context(|| {
+                            format!(
+                                "{name} was restarted {} times within {:?}, giving up",
+                                restarts.len(),
+                                policy.window
+                            )
+                        });
+                }
+
+                match &result {
+                    Ok(_) => warn!("task {name} exited, restarting in {backoff:?}"),
+                    Err(e) => error!("task {name} failed, restarting in {backoff:?}\n{e:?}"),
                 }
+
+                task.update_status(&name, |status| status.finished(&result));
+
+                restarts.push_back(now);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(policy.max_backoff);
+
+                task.update_status(&name, |status| {
+                    status.state = TaskState::Running;
+                    status.restarts += 1;
+                });
             }
-        });
+        };
 
-        self.tx
-            .send(TaskHandle { label, handle })
-            .expect("Could not tell task group about join handle");
+        self.start(label, supervised);
     }
 
-    /// Spawn a named task, if there is a task already running with the same name it will be killed beforehand
-    /// Only difference to spawn is the ability to pass some data
-    pub fn spawn_with_argument<L, A, F>(&self, label: L, argument: A, callback: TaskFnArg<A, F>)
+    fn start<C, F>(&self, label: String, callback: C)
     where
+        C: FnOnce(Task) -> F,
         F: Future<Output = Result<()>> + Send + 'static,
-        L: Into<String>,
-        A: Send + 'static,
     {
-        let label = label.into();
         let (tx, rx) = oneshot::channel();
+        let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
 
-        if let Some(old) = self.running.insert(label.clone(), tx) {
-            old.send(()).expect("Close previous task");
+        if let Some((_, old)) = self.running.insert(label.clone(), (generation, tx)) {
+            // The previous task might have finished on its own in the meantime
+            let _ = old.send(STOP_GRACE);
         }
 
-        let task = self.clone();
+        let status = TaskStatus {
+            label: label.clone(),
+            state: TaskState::Running,
+            started: OffsetDateTime::now_utc(),
+            restarts: 0,
+            last_error: None,
+        };
+
+        self.status.insert(label.clone(), status.clone());
+        STATUS.publish(status);
+
         let clean_up = label.clone();
-        let running = task.running.clone();
+        let lifecycle = Arc::new(Lifecycle::default());
+        let task = Task {
+            lifecycle: lifecycle.clone(),
+            ..self.clone()
+        };
+        let future = callback(task.clone());
 
         let handle = tokio::spawn(async move {
-            tokio::select! {
-                _ = rx => Ok(()),
-                result = callback(argument, task) => {
-                    running.remove(&clean_up);
-                    result
+            tokio::pin!(future);
+
+            let grace = tokio::select! {
+                // Whoever stopped or replaced the task takes care of the status
+                grace = rx => grace.unwrap_or_default(),
+                result = &mut future => {
+                    // Only if the label is still ours, it could already belong to a newer task
+                    let ours = task
+                        .running
+                        .remove_if(&clean_up, |_, (running, _)| *running == generation);
+
+                    if ours.is_some() {
+                        task.update_status(&clean_up, |status| status.finished(&result));
+                    }
+
+                    return result;
+                }
+            };
+
+            lifecycle.stop();
+
+            let wrap_up = async {
+                tokio::select! {
+                    // The task gets to see it is stopping before we look at whether it is busy
+                    biased;
+                    result = &mut future => result,
+                    _ = lifecycle.idle() => Ok(()),
                 }
+            };
+
+            match tokio::time::timeout(grace, wrap_up).await {
+                Ok(result) => result,
+                Err(_) => anyhow::bail!("{clean_up} was still busy after {grace:?}"),
             }
         });
 
@@ -87,9 +346,140 @@ impl Task {
             .expect("Could not tell task group about join handle");
     }
 
+    /// Stop a running task, returns false if there was no task with that label
+    pub fn stop(&self, label: &str) -> bool {
+        stop(&self.running, &self.status, label, STOP_GRACE)
+    }
+
+    /// Stop a task for good and drop its status, for tasks of things that were deleted
+    pub fn forget(&self, label: &str) -> bool {
+        let stopped = self.stop(label);
+        self.status.remove(label);
+        stopped
+    }
+
+    /// Resolves once the task this handle was given to is asked to stop
+    pub async fn stopping(&self) {
+        self.lifecycle.stopping().await
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.lifecycle.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Work that should not be cut off, like a database transaction
+    ///
+    /// A stopped task is dropped as soon as nothing holds on to a [`Busy`],
+    /// or when its grace period runs out
+    pub fn busy(&self) -> Busy {
+        self.lifecycle.busy.fetch_add(1, Ordering::SeqCst);
+        Busy(self.lifecycle.clone())
+    }
+
+    /// Ends the stream once the task is asked to stop, values that were already
+    /// waiting are handed out first
+    pub fn until_stopped<S>(&self, stream: S) -> BoxStream<'static, S::Item>
+    where
+        S: Stream + Unpin + Send + 'static,
+        S::Item: Send,
+    {
+        let task = self.clone();
+
+        stream::unfold((stream, None), move |(mut stream, busy)| {
+            let task = task.clone();
+
+            async move {
+                if busy.is_none() {
+                    tokio::select! {
+                        next = stream.next() => return next.map(|item| (item, (stream, None))),
+                        _ = task.stopping() => {}
+                    }
+                }
+
+                // Busy until the stream is drained, so the task is not dropped halfway
+                let busy = busy.unwrap_or_else(|| task.busy());
+                let item = stream.next().now_or_never().flatten()?;
+
+                Some((item, (stream, Some(busy))))
+            }
+        })
+        .boxed()
+    }
+
     pub fn has_task(&self, name: &str) -> bool {
         self.running.contains_key(name)
     }
+
+    /// Status of all tasks that have been spawned, ordered by label
+    pub fn status(&self) -> Vec<TaskStatus> {
+        sorted(&self.status)
+    }
+
+    /// Every change to the status of a task, after falling behind the status of every task is sent again
+    pub fn status_changes(&self) -> impl Stream<Item = TaskStatus> {
+        let status = self.status.clone();
+
+        STATUS
+            .subscribe_lagged()
+            .flat_map(move |change| match change {
+                Ok(change) => stream::iter(vec![change]),
+                Err(_) => stream::iter(sorted(&status)),
+            })
+    }
+
+    fn update_status(&self, label: &str, update: impl FnOnce(&mut TaskStatus)) {
+        update_status(&self.status, label, update);
+    }
+}
+
+fn stop(
+    running: &Running,
+    status: &DashMap<String, TaskStatus>,
+    label: &str,
+    grace: Duration,
+) -> bool {
+    match running.remove(label) {
+        Some((_, (_, tx))) => {
+            // The task might have finished on its own in the meantime
+            let _ = tx.send(grace);
+            update_status(status, label, |status| status.state = TaskState::Exited);
+            true
+        }
+        None => false,
+    }
+}
+
+fn sorted(status: &DashMap<String, TaskStatus>) -> Vec<TaskStatus> {
+    let mut status: Vec<TaskStatus> = status.iter().map(|s| s.value().clone()).collect();
+    status.sort_by(|a, b| a.label.cmp(&b.label));
+    status
+}
+
+fn update_status(
+    status: &DashMap<String, TaskStatus>,
+    label: &str,
+    update: impl FnOnce(&mut TaskStatus),
+) {
+    let Some(mut status) = status.get_mut(label) else {
+        return;
+    };
+
+    update(&mut status);
+
+    let status = status.clone();
+    STATUS.publish(status);
+}
+
+impl TaskStatus {
+    fn finished(&mut self, result: &Result<()>) {
+        match result {
+            Ok(_) => self.state = TaskState::Exited,
+            Err(e) => {
+                self.state = TaskState::Failed;
+                self.last_error = Some(format!("{e:#}"));
+            }
+        }
+    }
 }
 
 pub fn create_group<F>(callback: TaskFn<F>) -> Group
@@ -97,20 +487,31 @@ where
     F: Future<Output = Result<()>> + Send + 'static,
 {
     let (etx, erx) = oneshot::channel();
-    let running = Arc::new(dashmap::DashMap::new());
+    let running = Arc::new(DashMap::new());
+    let status = Arc::new(DashMap::new());
 
-    running.insert("init".into(), etx);
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+    running.insert("init".into(), (generation, etx));
 
     let (tx, rx) = flume::unbounded();
 
+    let task = Task {
+        running: running.clone(),
+        status: status.clone(),
+        tx,
+        lifecycle: Default::default(),
+    };
+
     let handle = TaskHandle {
         label: "init".into(),
 
         handle: tokio::spawn(async move {
+            let running = task.running.clone();
+
             tokio::select! {
                 _ = erx => Ok(()),
-                result = callback(Task { running: running.clone(), tx }) => {
-                    running.remove("init");
+                result = callback(task) => {
+                    running.remove_if("init", |_, (running, _)| *running == generation);
                     result
                 }
             }
@@ -118,8 +519,11 @@ where
     };
 
     Group {
+        running,
+        status,
         rx,
         track: vec![handle].into_iter().collect(),
+        failed: 0,
     }
 }
 
@@ -128,8 +532,12 @@ where
  * The initial task gives you the oportunity to spawn more tasks into the same group
  */
 pub struct Group {
+    running: Arc<Running>,
+    status: Arc<DashMap<String, TaskStatus>>,
     track: FuturesUnordered<TaskHandle>,
     rx: Receiver<TaskHandle>,
+    /// How many tasks ended with an error
+    failed: usize,
 }
 
 impl Group {
@@ -137,10 +545,7 @@ impl Group {
         loop {
             tokio::select! {
                 Some((id, result)) = self.track.next() => {
-                    match result {
-                        Ok(_) => debug!("task exit {id}"),
-                        Err(e) => error!("task failed {id} with error {e:?}"),
-                    }
+                    self.finished(id, result);
                 },
                 Ok(add) =  self.rx.recv_async() => {
                     debug!("task spawned {}", add.label);
@@ -152,6 +557,78 @@ impl Group {
             }
         }
     }
+
+    /// Stop every task except the ones in `keep` and wait for them to finish,
+    /// tasks that are still busy after `grace` are aborted
+    ///
+    /// Returns how many tasks failed while shutting down
+    pub async fn shutdown(&mut self, keep: &[&str], grace: Duration) -> usize {
+        let deadline = tokio::time::Instant::now() + grace;
+        let failed = self.failed;
+        let mut aborted = false;
+
+        loop {
+            // Tasks can still spawn others while they are being stopped
+            while let Ok(add) = self.rx.try_recv() {
+                self.track.push(add);
+            }
+
+            let labels: Vec<String> = self.running.iter().map(|r| r.key().clone()).collect();
+
+            let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+
+            for label in labels.iter().filter(|l| !keep.contains(&l.as_str())) {
+                stop(&self.running, &self.status, label, left);
+            }
+
+            if self.track.iter().all(|h| keep.contains(&h.label.as_str())) {
+                return self.failed - failed;
+            }
+
+            if aborted {
+                if let Some((id, result)) = self.track.next().await {
+                    self.finished(id, result);
+                }
+
+                continue;
+            }
+
+            match tokio::time::timeout_at(deadline, self.track.next()).await {
+                Ok(Some((id, result))) => self.finished(id, result),
+                Ok(None) => return self.failed - failed,
+                Err(_) => {
+                    for handle in self.track.iter() {
+                        if !keep.contains(&handle.label.as_str()) {
+                            warn!("task {} did not stop in {grace:?}, aborting", handle.label);
+                            handle.handle.abort();
+                        }
+                    }
+
+                    aborted = true;
+                }
+            }
+        }
+    }
+
+    /// Status of all tasks that have been spawned into the group, ordered by label
+    pub fn status(&self) -> Vec<TaskStatus> {
+        sorted(&self.status)
+    }
+
+    /// How many tasks ended with an error, restarts of supervised tasks are not counted
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    fn finished(&mut self, id: String, result: Result<()>) {
+        match result {
+            Ok(_) => debug!("task exit {id}"),
+            Err(e) => {
+                error!("task failed {id} with error {e:?}");
+                self.failed += 1;
+            }
+        }
+    }
 }
 
 pub struct TaskHandle {
@@ -174,3 +651,187 @@ impl Future for TaskHandle {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use futures::StreamExt;
+
+    use super::{create_group, Restart, RestartPolicy, Task, TaskState};
+
+    const POLICY: RestartPolicy = RestartPolicy {
+        restart: Restart::OnFailure,
+        backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(4),
+        max_restarts: 3,
+        window: Duration::from_secs(60),
+    };
+
+    async fn flaky(runs: Arc<AtomicUsize>, _: Task) -> Result<()> {
+        if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+            anyhow::bail!("not yet");
+        }
+
+        Ok(())
+    }
+
+    async fn broken(_: (), _: Task) -> Result<()> {
+        anyhow::bail!("always broken")
+    }
+
+    async fn init(task: Task) -> Result<()> {
+        task.spawn_supervised("flaky", Arc::new(AtomicUsize::new(0)), POLICY, flaky);
+        task.spawn_supervised("broken", (), POLICY, broken);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restarts_until_it_gives_up() {
+        let mut group = create_group(init);
+        group.complete().await.unwrap();
+
+        let status = group.status();
+
+        // The first run and three restarts
+        assert_eq!(status[0].label, "broken");
+        assert_eq!(status[0].state, TaskState::Failed);
+        assert_eq!(status[0].restarts, 3);
+        assert!(status[0]
+            .last_error
+            .as_ref()
+            .unwrap()
+            .contains("always broken"));
+
+        // Failed twice, then succeeded
+        assert_eq!(status[1].label, "flaky");
+        assert_eq!(status[1].state, TaskState::Exited);
+        assert_eq!(status[1].restarts, 2);
+    }
+
+    async fn forever(_: Task) -> Result<()> {
+        futures::future::pending().await
+    }
+
+    async fn spawn_forever(task: Task) -> Result<()> {
+        task.spawn("kept", forever);
+        task.spawn("stopped", forever);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_keeps_what_it_is_told_to() {
+        let mut group = create_group(spawn_forever);
+
+        // Give init the chance to spawn its tasks
+        let spawned = tokio::time::timeout(Duration::from_millis(50), group.complete()).await;
+        assert!(spawned.is_err());
+
+        assert_eq!(group.shutdown(&["kept"], Duration::from_secs(1)).await, 0);
+
+        let states: Vec<_> = group
+            .status()
+            .into_iter()
+            .map(|s| (s.label, s.state))
+            .collect();
+        assert_eq!(
+            states,
+            [
+                ("kept".to_string(), TaskState::Running),
+                ("stopped".to_string(), TaskState::Exited)
+            ]
+        );
+
+        assert_eq!(group.shutdown(&[], Duration::from_secs(1)).await, 0);
+
+        assert!(group.status().iter().all(|s| s.state == TaskState::Exited));
+        // Nothing left to wait for
+        group.complete().await.unwrap();
+        assert_eq!(group.failed(), 0);
+    }
+
+    async fn wrap_up(task: Task) -> Result<()> {
+        let _busy = task.busy();
+        task.stopping().await;
+
+        // Work that is still in flight when the task is asked to stop
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        Ok(())
+    }
+
+    async fn stuck(task: Task) -> Result<()> {
+        let _busy = task.busy();
+        futures::future::pending().await
+    }
+
+    async fn spawn_busy(task: Task) -> Result<()> {
+        task.spawn("wrap_up", wrap_up);
+        task.spawn("idle", forever);
+
+        Ok(())
+    }
+
+    async fn spawn_stuck(task: Task) -> Result<()> {
+        task.spawn("stuck", stuck);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_busy_tasks() {
+        let mut group = create_group(spawn_busy);
+
+        let spawned = tokio::time::timeout(Duration::from_millis(50), group.complete()).await;
+        assert!(spawned.is_err());
+
+        let started = std::time::Instant::now();
+        assert_eq!(group.shutdown(&[], Duration::from_secs(5)).await, 0);
+
+        let took = started.elapsed();
+        assert!(took >= Duration::from_millis(50), "{took:?}");
+        assert!(took < Duration::from_secs(5), "{took:?}");
+    }
+
+    #[tokio::test]
+    async fn shutdown_gives_up_on_busy_tasks() {
+        let mut group = create_group(spawn_stuck);
+
+        let spawned = tokio::time::timeout(Duration::from_millis(50), group.complete()).await;
+        assert!(spawned.is_err());
+
+        assert_eq!(group.shutdown(&[], Duration::from_millis(50)).await, 1);
+    }
+
+    async fn fall_behind(task: Task) -> Result<()> {
+        let mut changes = task.status_changes().boxed();
+
+        task.spawn("counted", forever);
+
+        for _ in 0..300 {
+            task.update_status("counted", |status| status.restarts += 1);
+        }
+
+        // The oldest changes were dropped, so the current status is sent instead
+        let status = changes.next().await.unwrap();
+        anyhow::ensure!(status.label == "counted" && status.restarts == 300);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn status_changes_catch_up_after_falling_behind() {
+        let mut group = create_group(fall_behind);
+
+        let spawned = tokio::time::timeout(Duration::from_millis(50), group.complete()).await;
+        assert!(spawned.is_err());
+
+        assert_eq!(group.shutdown(&[], Duration::from_secs(1)).await, 0);
+        assert_eq!(group.failed(), 0);
+    }
+}
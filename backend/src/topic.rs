@@ -1,36 +1,80 @@
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::hash::Hash;
 use std::sync::{Arc, Mutex};
 
-use futures::{stream, Stream};
+use futures::{stream, Stream, StreamExt};
+use itertools::Itertools;
 use slotmap::{DefaultKey, SlotMap};
-use smallvec::{smallvec, SmallVec};
+use smallvec::SmallVec;
 use tokio::sync::Notify;
+use tracing::warn;
 
 macro_rules! static_topic {
     ($name:ident, $of:ty) => {
         static $name: once_cell::sync::Lazy<crate::topic::Topic<$of>> =
             once_cell::sync::Lazy::new(|| crate::topic::Topic::default());
     };
+    ($name:ident, $of:ty, $capacity:expr, $overflow:expr) => {
+        static $name: once_cell::sync::Lazy<crate::topic::Topic<$of>> =
+            once_cell::sync::Lazy::new(|| crate::topic::Topic::new($capacity, $overflow));
+    };
 }
 
 pub(crate) use static_topic;
 
+/// How many values a subscriber can fall behind unless the topic says otherwise
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// What happens when a subscriber has a full buffer and a value is published
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the oldest value, the subscriber is told how many it missed
+    DropOldest,
+    /// Keep every value, the buffer grows past capacity and that is logged.
+    /// For control messages that must not get lost and are handled promptly
+    Grow,
+}
+
+/// A subscriber fell behind and missed this many values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
 #[derive(Clone)]
 pub struct Topic<T> {
     subs: Arc<Mutex<SlotMap<DefaultKey, Subscriber<T>>>>,
     notify: Arc<Notify>,
+    capacity: usize,
+    overflow: Overflow,
 }
 
 impl<T> Topic<T>
 where
     T: Clone,
 {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        assert!(capacity > 0, "Topic needs room for at least one value");
+
+        Self {
+            subs: Default::default(),
+            notify: Default::default(),
+            capacity,
+            overflow,
+        }
+    }
+
+    /// Values published from now on, values missed because the subscriber fell behind are skipped
     pub fn subscribe(&self) -> impl Stream<Item = T> + '_ {
+        skip_lagged(self.subscribe_lagged())
+    }
+
+    /// Values published from now on, with an error in their place when the subscriber fell behind
+    pub fn subscribe_lagged(&self) -> impl Stream<Item = Result<T, Lagged>> + '_ {
         let mut subs = self.subs.lock().expect("Lock subscribers");
 
-        let mutex = Arc::new(Mutex::new(smallvec![]));
+        let buffer = Buffer::new(self.capacity);
 
         let key = subs.insert(Subscriber {
-            mutex: mutex.clone(),
+            buffer: buffer.clone(),
         });
 
         let guard = RemoveKey {
@@ -38,14 +82,9 @@ where
             subs: self.subs.clone(),
         };
 
-        let subscription = Subscription::new(mutex, self.notify.clone(), guard);
+        let subscription = Subscription::new(buffer, self.notify.clone(), guard);
 
-        let strm = stream::unfold(subscription, |mut sub| async move {
-            let val = sub.recv().await;
-            Some((val, sub))
-        });
-
-        Box::pin(strm)
+        subscription.into_stream()
     }
 
     pub fn publish(&self, payload: T) {
@@ -54,15 +93,17 @@ where
             let mut iter = subs.iter().peekable();
 
             while let Some((_, sub)) = iter.next() {
-                let mut buffer = sub.mutex.lock().expect("Locking subscriber buffer failed");
+                let mut buffer = sub.buffer.lock().expect("Locking subscriber buffer failed");
+
+                buffer.make_room(self.capacity, self.overflow);
 
                 if iter.peek().is_none() {
                     // We are at the last value
                     // So we can skip the last clone
-                    buffer.push(payload);
+                    buffer.values.push_back(payload);
                     break;
                 } else {
-                    buffer.push(payload.clone());
+                    buffer.values.push_back(payload.clone());
                 }
             }
         }
@@ -76,6 +117,8 @@ impl<T> Default for Topic<T> {
         Self {
             subs: Default::default(),
             notify: Default::default(),
+            capacity: DEFAULT_CAPACITY,
+            overflow: Overflow::DropOldest,
         }
     }
 }
@@ -92,41 +135,301 @@ impl<T> Drop for RemoveKey<T> {
     }
 }
 
+/// A topic where subscribers only get what is published under the keys they subscribed to,
+/// so publishing does not wake everyone
+pub struct KeyedTopic<K, T> {
+    index: Arc<Mutex<Index<K, T>>>,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+struct Index<K, T> {
+    subs: SlotMap<DefaultKey, KeyedSubscriber<K, T>>,
+    by_key: HashMap<K, SmallVec<[DefaultKey; 1]>>,
+}
+
+struct KeyedSubscriber<K, T> {
+    keys: Vec<K>,
+    buffer: Arc<Mutex<Buffer<T>>>,
+    /// Every subscriber is woken on its own
+    notify: Arc<Notify>,
+}
+
+impl<K, T> KeyedTopic<K, T>
+where
+    K: Hash + Eq + Clone,
+    T: Clone,
+{
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        assert!(capacity > 0, "Topic needs room for at least one value");
+
+        Self {
+            index: Arc::new(Mutex::new(Index {
+                subs: SlotMap::new(),
+                by_key: HashMap::new(),
+            })),
+            capacity,
+            overflow,
+        }
+    }
+
+    /// Values published under any of `keys` from now on, missed values are skipped
+    pub fn subscribe(&self, keys: &[K]) -> impl Stream<Item = T> + '_ {
+        skip_lagged(self.subscribe_lagged(keys))
+    }
+
+    /// Values published under any of `keys` from now on, with an error in their place
+    /// when the subscriber fell behind
+    pub fn subscribe_lagged(&self, keys: &[K]) -> impl Stream<Item = Result<T, Lagged>> + '_ {
+        let mut index = self.index.lock().expect("Lock subscribers");
+
+        let keys: Vec<K> = keys.iter().unique().cloned().collect();
+        let buffer = Buffer::new(self.capacity);
+        let notify = Arc::new(Notify::new());
+
+        let id = index.subs.insert(KeyedSubscriber {
+            keys: keys.clone(),
+            buffer: buffer.clone(),
+            notify: notify.clone(),
+        });
+
+        for key in keys {
+            index.by_key.entry(key).or_default().push(id);
+        }
+
+        let guard = RemoveSubscriber {
+            index: self.index.clone(),
+            id,
+        };
+
+        Subscription::new(buffer, notify, guard).into_stream()
+    }
+
+    pub fn publish(&self, key: &K, payload: T) {
+        let index = self.index.lock().expect("Lock subscribers failed");
+        let Index { subs, by_key } = &*index;
+
+        let Some(ids) = by_key.get(key) else {
+            return;
+        };
+
+        let mut iter = ids.iter().peekable();
+
+        while let Some(id) = iter.next() {
+            let sub = &subs[*id];
+            let mut buffer = sub.buffer.lock().expect("Locking subscriber buffer failed");
+
+            buffer.make_room(self.capacity, self.overflow);
+
+            if iter.peek().is_none() {
+                buffer.values.push_back(payload);
+                drop(buffer);
+                sub.notify.notify_one();
+                break;
+            } else {
+                buffer.values.push_back(payload.clone());
+            }
+
+            drop(buffer);
+            sub.notify.notify_one();
+        }
+    }
+}
+
+impl<K, T> Default for KeyedTopic<K, T>
+where
+    K: Hash + Eq + Clone,
+    T: Clone,
+{
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, Overflow::DropOldest)
+    }
+}
+
+impl<K, T> Index<K, T>
+where
+    K: Hash + Eq,
+{
+    fn remove(&mut self, id: DefaultKey) {
+        let Some(sub) = self.subs.remove(id) else {
+            return;
+        };
+
+        for key in sub.keys {
+            if let Entry::Occupied(mut ids) = self.by_key.entry(key) {
+                ids.get_mut().retain(|i| *i != id);
+
+                if ids.get().is_empty() {
+                    ids.remove();
+                }
+            }
+        }
+    }
+}
+
+pub struct RemoveSubscriber<K: Hash + Eq, T> {
+    index: Arc<Mutex<Index<K, T>>>,
+    id: DefaultKey,
+}
+
+impl<K: Hash + Eq, T> Drop for RemoveSubscriber<K, T> {
+    fn drop(&mut self) {
+        let mut index = self.index.lock().expect("Lock subscribers failed");
+        index.remove(self.id);
+    }
+}
+
+/// Skip over lagged errors, only logging them
+fn skip_lagged<T>(strm: impl Stream<Item = Result<T, Lagged>>) -> impl Stream<Item = T> {
+    let strm = strm.filter_map(|next| async move {
+        match next {
+            Ok(value) => Some(value),
+            Err(Lagged(missed)) => {
+                warn!("Subscriber fell behind and missed {missed} values");
+                None
+            }
+        }
+    });
+
+    Box::pin(strm)
+}
+
+struct Buffer<T> {
+    values: VecDeque<T>,
+    /// Values dropped since the subscriber last received
+    lagged: u64,
+}
+
+impl<T> Buffer<T> {
+    fn new(capacity: usize) -> Arc<Mutex<Buffer<T>>> {
+        Arc::new(Mutex::new(Buffer {
+            values: VecDeque::with_capacity(capacity.min(16)),
+            lagged: 0,
+        }))
+    }
+
+    /// Make room for one more value
+    fn make_room(&mut self, capacity: usize, overflow: Overflow) {
+        if self.values.len() < capacity {
+            return;
+        }
+
+        match overflow {
+            Overflow::DropOldest => {
+                self.values.pop_front();
+                self.lagged += 1;
+            }
+            Overflow::Grow => {
+                if self.values.len() == capacity {
+                    warn!("Subscriber is {capacity} values behind, its buffer keeps growing");
+                }
+            }
+        }
+    }
+}
+
 pub struct Subscriber<T> {
-    pub mutex: Arc<Mutex<SmallVec<[T; 1]>>>,
+    buffer: Arc<Mutex<Buffer<T>>>,
 }
 
-pub struct Subscription<T> {
-    mutex: Arc<Mutex<SmallVec<[T; 1]>>>,
+pub struct Subscription<T, G> {
+    buffer: Arc<Mutex<Buffer<T>>>,
     notify: Arc<Notify>,
 
-    _guard: RemoveKey<T>,
+    _guard: G,
 }
 
-impl<T> Subscription<T> {
-    pub fn new(
-        shared: Arc<Mutex<SmallVec<[T; 1]>>>,
-        notify: Arc<Notify>,
-        guard: RemoveKey<T>,
-    ) -> Subscription<T> {
+impl<T, G> Subscription<T, G> {
+    fn new(buffer: Arc<Mutex<Buffer<T>>>, notify: Arc<Notify>, guard: G) -> Subscription<T, G> {
         Subscription {
-            mutex: shared,
+            buffer,
             notify,
             _guard: guard,
         }
     }
 
-    pub async fn recv(&mut self) -> T {
+    fn into_stream(self) -> impl Stream<Item = Result<T, Lagged>> {
+        let strm = stream::unfold(self, |mut sub| async move {
+            let val = sub.recv().await;
+            Some((val, sub))
+        });
+
+        Box::pin(strm)
+    }
+
+    /// The next value, or how many were missed before it
+    pub async fn recv(&mut self) -> Result<T, Lagged> {
         loop {
+            // Listen before checking so we can not miss a publish
+            let notified = self.notify.notified();
+
             {
-                let mut buffer = self.mutex.lock().expect("Locking subscriber buffer failed");
+                let mut buffer = self
+                    .buffer
+                    .lock()
+                    .expect("Locking subscriber buffer failed");
 
-                if !buffer.is_empty() {
-                    return buffer.remove(0);
+                if buffer.lagged > 0 {
+                    let missed = std::mem::take(&mut buffer.lagged);
+                    return Err(Lagged(missed));
+                }
+
+                if let Some(value) = buffer.values.pop_front() {
+                    return Ok(value);
                 }
             }
 
-            self.notify.notified().await;
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+
+    use super::{KeyedTopic, Lagged, Overflow, Topic};
+
+    #[tokio::test]
+    async fn overflow() {
+        let topic = Topic::new(2, Overflow::DropOldest);
+        let mut values = topic.subscribe_lagged();
+
+        for i in 0..5 {
+            topic.publish(i);
+        }
+
+        assert_eq!(values.next().await, Some(Err(Lagged(3))));
+        assert_eq!(values.next().await, Some(Ok(3)));
+        assert_eq!(values.next().await, Some(Ok(4)));
+
+        let topic = Topic::new(2, Overflow::Grow);
+        let mut values = topic.subscribe_lagged();
+
+        for i in 0..5 {
+            topic.publish(i);
+        }
+
+        for i in 0..5 {
+            assert_eq!(values.next().await, Some(Ok(i)));
         }
     }
+
+    #[tokio::test]
+    async fn keyed() {
+        let topic = KeyedTopic::default();
+        let mut a = topic.subscribe(&["a"]);
+        let mut both = topic.subscribe(&["a", "b", "a"]);
+
+        topic.publish(&"b", 1);
+        topic.publish(&"c", 2);
+        topic.publish(&"a", 3);
+
+        assert_eq!(a.next().await, Some(3));
+        assert_eq!(both.next().await, Some(1));
+        assert_eq!(both.next().await, Some(3));
+
+        drop(both);
+        assert!(!topic.index.lock().unwrap().by_key.contains_key("b"));
+    }
 }
@@ -5,16 +5,28 @@ use dashmap::{mapref::one::Ref, DashMap};
 use futures::{Stream, StreamExt};
 use once_cell::sync::Lazy;
 use serde_json::Value as Json;
+use time::OffsetDateTime;
 use tracing::debug;
 
 use crate::strings::IString;
 use crate::task::Task;
-use crate::topic::static_topic;
+use crate::topic::{static_topic, KeyedTopic, Lagged, Overflow, DEFAULT_CAPACITY};
 
-static STORAGE: Lazy<DashMap<ValueId, Result<Json, String>>> = Lazy::new(DashMap::default);
+static STORAGE: Lazy<DashMap<ValueId, Current>> = Lazy::new(DashMap::default);
+/// Virtual features that are pulsed instead of holding the value pushed to them
+static MOMENTARY: Lazy<DashMap<ValueId, ()>> = Lazy::new(DashMap::default);
 
-static_topic!(INCOMING, (ValueId, Result<Json, String>));
-static_topic!(OUTGOING, (ValueId, Json));
+// Slow consumers like API clients must not hold up anyone, they are told what they missed
+static_topic!(INCOMING, (ValueId, Current), 4096, Overflow::DropOldest);
+// Pushes are commands for devices, they are never dropped
+static_topic!(OUTGOING, (ValueId, Json), DEFAULT_CAPACITY, Overflow::Grow);
+
+/// Updates by the value they are for
+static INCOMING_BY_VALUE: Lazy<KeyedTopic<ValueId, (ValueId, Current)>> =
+    Lazy::new(|| KeyedTopic::new(DEFAULT_CAPACITY, Overflow::DropOldest));
+/// Pushes by the device they go to
+static OUTGOING_BY_DEVICE: Lazy<KeyedTopic<IString, (ValueId, Json)>> =
+    Lazy::new(|| KeyedTopic::new(DEFAULT_CAPACITY, Overflow::Grow));
 
 pub async fn catch_virtual_push(_: Task) -> Result<()> {
     let mut values = push_subscribe().filter(|(id, _)| {
@@ -23,12 +35,42 @@ pub async fn catch_virtual_push(_: Task) -> Result<()> {
     });
 
     while let Some((vid, value)) = values.next().await {
-        set_current(vid, Ok(value));
+        if !MOMENTARY.contains_key(&vid) {
+            set_current(vid, Ok(value));
+            continue;
+        }
+
+        // A press is an edge, subscribers see the value go high and straight back low
+        if value == Json::Bool(true) {
+            set_current(vid, Ok(Json::Bool(true)));
+            set_current(vid, Ok(Json::Bool(false)));
+        }
     }
 
     Ok(())
 }
 
+/// The current value of a feature and when it was reported
+#[derive(Clone, Debug, PartialEq)]
+pub struct Current {
+    pub value: Result<Json, String>,
+    /// Last time the value was reported, even if it did not change.
+    /// None if it has not been reported since startup
+    pub last_updated: Option<OffsetDateTime>,
+    /// Last time the value changed, None if it has not changed since startup
+    pub last_changed: Option<OffsetDateTime>,
+}
+
+impl Default for Current {
+    fn default() -> Self {
+        Current {
+            value: Ok(Json::Null),
+            last_updated: None,
+            last_changed: None,
+        }
+    }
+}
+
 #[derive(Hash, Eq, PartialEq, Copy, Clone, Debug, Ord, PartialOrd)]
 pub struct ValueId {
     pub device: IString,
@@ -48,33 +90,109 @@ impl ValueId {
     }
 }
 
-pub fn current(key: ValueId) -> Ref<'static, ValueId, Result<Json, String>> {
-    STORAGE.entry(key).or_insert(Ok(Json::Null)).downgrade()
+pub fn current(key: ValueId) -> Ref<'static, ValueId, Current> {
+    STORAGE.entry(key).or_default().downgrade()
+}
+
+/// Put a value back into storage without telling subscribers,
+/// used on startup before anything listens. A value that is already known wins
+pub fn restore(key: ValueId, value: Json) {
+    STORAGE.entry(key).or_insert(Current {
+        value: Ok(value),
+        ..Default::default()
+    });
 }
 
 pub fn set_current(key: ValueId, value: Result<Json, String>) {
-    let same = if let Some(current) = STORAGE.get(&key) {
-        *current == value
-    } else {
-        false
-    };
+    let now = OffsetDateTime::now_utc();
+
+    let mut current = STORAGE.entry(key).or_default();
+
+    current.last_updated = Some(now);
 
-    if !same {
+    if current.value != value {
         debug!("{:?} update source {:?}", key, value);
 
-        STORAGE.insert(key, value.clone());
-        INCOMING.publish((key, value));
+        current.value = value;
+        current.last_changed = Some(now);
+
+        let update = current.clone();
+        drop(current);
+
+        publish(key, update);
     }
 }
 
-pub fn subscribe() -> impl Stream<Item = (ValueId, Result<Json, String>)> {
+/// Replace the value with an error because the source has gone quiet,
+/// unlike [`set_current`] this does not count as an update
+pub fn set_stale(key: ValueId, message: String) {
+    let mut current = STORAGE.entry(key).or_default();
+
+    let value = Err(message);
+
+    if current.value != value {
+        debug!("{:?} is stale", key);
+
+        current.value = value;
+        current.last_changed = Some(OffsetDateTime::now_utc());
+
+        let update = current.clone();
+        drop(current);
+
+        publish(key, update);
+    }
+}
+
+/// Drop the value of a feature that no longer exists
+pub fn forget(key: ValueId) {
+    STORAGE.remove(&key);
+    MOMENTARY.remove(&key);
+}
+
+/// Pushing `true` to a momentary virtual feature pulses it, anything else is ignored
+pub fn set_momentary(key: ValueId) {
+    MOMENTARY.insert(key, ());
+}
+
+fn publish(key: ValueId, update: Current) {
+    INCOMING_BY_VALUE.publish(&key, (key, update.clone()));
+    INCOMING.publish((key, update));
+}
+
+pub fn subscribe() -> impl Stream<Item = (ValueId, Current)> {
     INCOMING.subscribe()
 }
 
+/// Updates to all values, tells when values were missed because the subscriber fell behind
+pub fn subscribe_lagged() -> impl Stream<Item = Result<(ValueId, Current), Lagged>> {
+    INCOMING.subscribe_lagged()
+}
+
+/// The values that are known right now
+pub fn all() -> Vec<(ValueId, Current)> {
+    STORAGE
+        .iter()
+        .map(|entry| (*entry.key(), entry.value().clone()))
+        .collect()
+}
+
+/// Updates to the given values only, tells when values were missed because the subscriber fell behind
+pub fn subscribe_values(
+    keys: &[ValueId],
+) -> impl Stream<Item = Result<(ValueId, Current), Lagged>> {
+    INCOMING_BY_VALUE.subscribe_lagged(keys)
+}
+
 pub fn push(key: ValueId, value: Json) {
+    OUTGOING_BY_DEVICE.publish(&key.device, (key, value.clone()));
     OUTGOING.publish((key, value));
 }
 
 pub fn push_subscribe() -> impl Stream<Item = (ValueId, Json)> {
     OUTGOING.subscribe()
 }
+
+/// Values pushed to the features of one device
+pub fn push_subscribe_device(device: IString) -> impl Stream<Item = (ValueId, Json)> {
+    OUTGOING_BY_DEVICE.subscribe(&[device])
+}